@@ -16,6 +16,8 @@ fn find_header(header_path: &str) -> Option<PathBuf> {
 fn main() {
     let license_path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/LICENSE"));
     let include_path = find_header("deadbeef/deadbeef.h").expect("");
+    // Optional: without it local covers are unavailable, everything else still builds.
+    let artwork_include_path = find_header("deadbeef/artwork.h");
 
     let mut content = fs::read(license_path).expect("Failed to read LICENSE");
 
@@ -24,10 +26,19 @@ fn main() {
     // The bindgen::Builder is the main entry point
     // to bindgen, and lets you build up options for
     // the resulting bindings.
-    let bindings = bindgen::Builder::default()
+    let mut builder = bindgen::Builder::default()
         // The input header we would like to generate
         // bindings for.
-        .header(include_path.to_str().unwrap())
+        .header(include_path.to_str().unwrap());
+
+    println!("cargo::rustc-check-cfg=cfg(artwork_plugin)");
+
+    if let Some(artwork_include_path) = artwork_include_path {
+        builder = builder.header(artwork_include_path.to_str().unwrap());
+        println!("cargo::rustc-cfg=artwork_plugin");
+    }
+
+    let bindings = builder
         // Tell cargo to invalidate the built crate whenever any of the
         // included header files changed.
         .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
@@ -0,0 +1,86 @@
+use std::{ffi::c_int, ptr, sync::LazyLock};
+
+use crate::{
+    API,
+    deadbeef::{
+        DB_ACTION_ADD_MENU, DB_ACTION_COMMON, DB_ACTION_SINGLE_TRACK, DB_plugin_action_t,
+        ddb_action_context_t,
+    },
+    overrides::edit_selected_override,
+    status::show_status,
+    util::catch_panic,
+};
+
+pub static ACTIONS: LazyLock<SafeDBActions> = LazyLock::new(|| {
+    let mut edit_cover_override: DB_plugin_action_t = unsafe { std::mem::zeroed() };
+
+    // Track context menu only; the title is not a main menu path.
+    edit_cover_override.title = c"Edit Discord Cover Override".as_ptr();
+    edit_cover_override.name = c"discordrpc_edit_cover_override".as_ptr();
+    edit_cover_override.flags = DB_ACTION_SINGLE_TRACK;
+    edit_cover_override.callback2 = Some(edit_cover_override_action);
+
+    let mut show_status: DB_plugin_action_t = unsafe { std::mem::zeroed() };
+
+    show_status.title = c"Help/Discord Rich Presence Status".as_ptr();
+    show_status.name = c"discordrpc_show_status".as_ptr();
+    show_status.flags = DB_ACTION_COMMON | DB_ACTION_ADD_MENU;
+    show_status.callback2 = Some(show_status_action);
+
+    let mut actions = vec![edit_cover_override, show_status];
+
+    let mut next = ptr::null_mut();
+
+    // The vector is never resized once built, so the `next` chain can point into it.
+    for action in actions.iter_mut().rev() {
+        action.next = next;
+        next = ptr::addr_of_mut!(*action);
+    }
+
+    SafeDBActions(actions)
+});
+
+pub struct SafeDBActions(pub Vec<DB_plugin_action_t>);
+
+impl SafeDBActions {
+    pub fn first(&self) -> *mut DB_plugin_action_t {
+        self.0
+            .first()
+            .map_or(ptr::null_mut(), |action| ptr::addr_of!(*action).cast_mut())
+    }
+}
+
+unsafe extern "C" fn edit_cover_override_action(
+    _: *mut DB_plugin_action_t,
+    _: ddb_action_context_t,
+) -> c_int {
+    catch_panic("edit_cover_override_action", -1, || {
+        let api = API.get().unwrap();
+
+        if let Err(e) = edit_selected_override() {
+            api.log_error(format!("Failed to open cover overrides: {:?}", e));
+            -1
+        } else {
+            0
+        }
+    })
+}
+
+unsafe extern "C" fn show_status_action(
+    _: *mut DB_plugin_action_t,
+    _: ddb_action_context_t,
+) -> c_int {
+    catch_panic("show_status_action", -1, || {
+        let api = API.get().unwrap();
+
+        if let Err(e) = show_status() {
+            api.log_error(format!("Failed to show plugin status: {:?}", e));
+            -1
+        } else {
+            0
+        }
+    })
+}
+
+unsafe impl Sync for SafeDBActions {}
+unsafe impl Send for SafeDBActions {}
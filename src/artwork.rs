@@ -0,0 +1,142 @@
+use std::io::Cursor;
+#[cfg(artwork_plugin)]
+use std::{
+    ffi::{CStr, c_int, c_void},
+    fs, mem, slice,
+    sync::mpsc::{self, SyncSender},
+    time::Duration,
+};
+
+use image::{DynamicImage, codecs::jpeg::JpegEncoder};
+
+#[cfg(artwork_plugin)]
+use crate::{
+    API,
+    deadbeef::{
+        DDB_ARTWORK_FLAG_LOAD_BLOB, ddb_artwork_plugin_t, ddb_cover_info_t, ddb_cover_query_t,
+    },
+    util::catch_panic,
+};
+use crate::{
+    deadbeef::safe_wrapper::SafeDBPlayItem,
+    error::{Error, Result},
+};
+
+/// How long to wait for the artwork plugin before giving up on a local cover.
+#[cfg(artwork_plugin)]
+const ARTWORK_TIMEOUT: Duration = Duration::from_secs(10);
+const JPEG_QUALITY: u8 = 85;
+
+#[cfg(artwork_plugin)]
+type CoverSender = SyncSender<Option<Vec<u8>>>;
+
+#[cfg(artwork_plugin)]
+fn artwork_plugin() -> Result<&'static ddb_artwork_plugin_t> {
+    let api = API.get().unwrap();
+    let plugin = api.plug_get_for_id(c"artwork2")? as *const ddb_artwork_plugin_t;
+
+    unsafe { plugin.as_ref() }.ok_or(Error::ArtworkPluginMissing)
+}
+
+/// Built without `deadbeef/artwork.h`, so there is no artwork plugin to ask.
+#[cfg(not(artwork_plugin))]
+pub fn local_cover(_: &SafeDBPlayItem) -> Result<Vec<u8>> {
+    Err(Error::ArtworkPluginMissing)
+}
+
+/// Asks DeaDBeeF's artwork plugin for the embedded or folder cover of `item`.
+#[cfg(artwork_plugin)]
+pub fn local_cover(item: &SafeDBPlayItem) -> Result<Vec<u8>> {
+    let api = API.get().unwrap();
+    let cover_get = artwork_plugin()?
+        .cover_get
+        .ok_or(Error::ArtworkPluginMissing)?;
+
+    if item.is_null() {
+        return Err(Error::ArtworkNotFound);
+    }
+
+    let (sender, receiver) = mpsc::sync_channel::<Option<Vec<u8>>>(1);
+    let mut query: Box<ddb_cover_query_t> = unsafe { Box::new(mem::zeroed()) };
+
+    api.pl_item_ref(item.as_ptr())?;
+    query._size = mem::size_of::<ddb_cover_query_t>() as _;
+    query.flags = DDB_ARTWORK_FLAG_LOAD_BLOB as _;
+    query.track = item.as_ptr();
+    query.user_data = Box::into_raw(Box::new(sender)) as *mut c_void;
+
+    // The callback owns the query from here on, even if we stop waiting for it.
+    unsafe { cover_get(Box::into_raw(query), Some(cover_callback)) };
+
+    match receiver.recv_timeout(ARTWORK_TIMEOUT) {
+        Ok(Some(data)) => Ok(data),
+        _ => Err(Error::ArtworkNotFound),
+    }
+}
+
+#[cfg(artwork_plugin)]
+extern "C" fn cover_callback(
+    error: c_int,
+    query: *mut ddb_cover_query_t,
+    cover: *mut ddb_cover_info_t,
+) {
+    catch_panic("cover_callback", (), || {
+        let api = API.get().unwrap();
+        let query = unsafe { Box::from_raw(query) };
+        let sender = unsafe { Box::from_raw(query.user_data as *mut CoverSender) };
+        let data = match unsafe { cover.as_ref() } {
+            Some(info) if error == 0 && info.cover_found != 0 => read_cover(info),
+            _ => None,
+        };
+
+        let _ = sender.try_send(data);
+
+        if !cover.is_null()
+            && let Ok(plugin) = artwork_plugin()
+            && let Some(cover_info_release) = plugin.cover_info_release
+        {
+            unsafe { cover_info_release(cover) };
+        }
+
+        api.pl_item_unref(query.track).ok();
+    });
+}
+
+#[cfg(artwork_plugin)]
+fn read_cover(info: &ddb_cover_info_t) -> Option<Vec<u8>> {
+    if !info.blob.is_null() && info.blob_image_size > 0 {
+        let data = unsafe {
+            slice::from_raw_parts(
+                (info.blob as *const u8).add(info.blob_image_offset as usize),
+                info.blob_image_size as usize,
+            )
+        };
+
+        Some(data.to_vec())
+    } else if !info.image_filename.is_null() {
+        let path = unsafe { CStr::from_ptr(info.image_filename) };
+
+        fs::read(path.to_string_lossy().as_ref()).ok()
+    } else {
+        None
+    }
+}
+
+/// Scales `data` down to fit within `max_size` pixels (if given) and re-encodes it as JPEG.
+pub fn prepare_cover(data: &[u8], max_size: Option<u32>) -> Result<Vec<u8>> {
+    let mut cover = image::load_from_memory(data).map_err(Error::ImageFailed)?;
+
+    if let Some(max_size) = max_size
+        && (cover.width() > max_size || cover.height() > max_size)
+    {
+        cover = cover.thumbnail(max_size, max_size);
+    }
+
+    let mut out = Cursor::new(Vec::new());
+
+    JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY)
+        .encode_image(&DynamicImage::ImageRgb8(cover.to_rgb8()))
+        .map_err(Error::ImageFailed)?;
+
+    Ok(out.into_inner())
+}
@@ -0,0 +1,314 @@
+use std::{fs, path::PathBuf};
+
+use regex::Regex;
+
+use crate::{
+    API,
+    config::{ConfigDefault, ConfigKey},
+    error::Result,
+    util::plugin_config_dir,
+};
+
+/// Built-in rules as pattern and replacement. Trailing matches stop at an unescaped double quote
+/// so they also work inside MusicBrainz queries like `release:"..." AND artist:"..."`.
+const PRESETS: &[(&str, &[(&str, &str)])] = &[
+    (
+        "remaster",
+        &[
+            (r"(?i)\s*[(\[][^)\]]*\bremaster(ed)?\b[^)\]]*[)\]]", ""),
+            (
+                r"(?i)\s+-\s+(\d{4}\s+)?(digital(ly)?\s+)?remaster(ed)?(\s+\d{4})?(\s+version)?\b",
+                "",
+            ),
+        ],
+    ),
+    (
+        "explicit",
+        &[(r"(?i)\s*[(\[](explicit|clean)( version)?[)\]]", "")],
+    ),
+    (
+        "feat",
+        &[
+            (r"(?i)\s*[(\[](feat\.?|ft\.?|featuring)\s[^)\]]*[)\]]", ""),
+            (r#"(?i)\s+(feat\.|ft\.|featuring)\s([^"\\]|\\.)*"#, ""),
+        ],
+    ),
+    (
+        "live",
+        &[
+            (r"(?i)\s*[(\[]live\b[^)\]]*[)\]]", ""),
+            (r#"(?i)\s+-\s+live\b([^"\\]|\\.)*"#, ""),
+        ],
+    ),
+    ("brackets", &[(r#"(\s*[(\[][^)\]]*[)\]])+\s*("|$)"#, "$2")]),
+];
+
+/// Which text a set of rules is applied to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CleanupTarget {
+    /// Details, state and icon text shown on Discord.
+    Presence,
+    /// The MusicBrainz album query.
+    MusicBrainz,
+}
+
+impl CleanupTarget {
+    fn section(self) -> &'static str {
+        match self {
+            CleanupTarget::Presence => "presence",
+            CleanupTarget::MusicBrainz => "musicbrainz",
+        }
+    }
+}
+
+struct Rule {
+    pattern: Regex,
+    replacement: String,
+}
+
+fn rules_path() -> Result<PathBuf> {
+    Ok(plugin_config_dir()?.join("cleanup_rules.txt"))
+}
+
+fn compile(pattern: &str, replacement: &str) -> Option<Rule> {
+    match Regex::new(pattern) {
+        Ok(pattern) => Some(Rule {
+            pattern,
+            replacement: replacement.to_string(),
+        }),
+        Err(e) => {
+            API.get()
+                .unwrap()
+                .log_warn(format!("Ignoring cleanup rule '{}': {}", pattern, e));
+            None
+        }
+    }
+}
+
+fn preset_rules(names: &str) -> Vec<Rule> {
+    let api = API.get().unwrap();
+    let mut rules = Vec::new();
+
+    for name in names
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+    {
+        match PRESETS.iter().find(|(preset, _)| *preset == name) {
+            Some((_, patterns)) => rules.extend(
+                patterns
+                    .iter()
+                    .filter_map(|(pattern, replacement)| compile(pattern, replacement)),
+            ),
+            None => api.log_warn(format!("Unknown cleanup preset '{}'.", name)),
+        }
+    }
+
+    rules
+}
+
+/// Parses `pattern => replacement` lines from the `[presence]` or `[musicbrainz]` section.
+fn custom_rules(content: &str, target: CleanupTarget) -> Vec<Rule> {
+    let mut section = String::new();
+    let mut rules = Vec::new();
+
+    for line in content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            section = name.trim().to_lowercase();
+        } else if section == target.section()
+            && let Some((pattern, replacement)) = line.split_once(" =>")
+            && let Some(rule) = compile(pattern.trim(), replacement.trim())
+        {
+            rules.push(rule);
+        }
+    }
+
+    rules
+}
+
+/// The preset and custom rules configured for one target, in the order they are applied.
+pub struct Cleanup(Vec<Rule>);
+
+impl Cleanup {
+    pub fn load(target: CleanupTarget) -> Result<Self> {
+        let api = API.get().unwrap();
+        let presets = match target {
+            CleanupTarget::Presence => api.conf_get_str(
+                ConfigKey::CLEANUP_PRESENCE_PRESETS,
+                ConfigDefault::CLEANUP_PRESENCE_PRESETS,
+            )?,
+            CleanupTarget::MusicBrainz => api.conf_get_str(
+                ConfigKey::CLEANUP_QUERY_PRESETS,
+                ConfigDefault::CLEANUP_QUERY_PRESETS,
+            )?,
+        };
+        let mut rules = preset_rules(&presets);
+
+        if let Ok(content) = fs::read_to_string(rules_path()?) {
+            rules.extend(custom_rules(&content, target));
+        }
+
+        Ok(Self(rules))
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        let cleaned = self.0.iter().fold(text.to_string(), |text, rule| {
+            rule.pattern
+                .replace_all(&text, rule.replacement.as_str())
+                .into_owned()
+        });
+        let cleaned = cleaned.trim();
+
+        // A field that consists only of what the rules strip (e.g. "(Live)") is kept as it is.
+        if cleaned.is_empty() {
+            text.to_string()
+        } else {
+            cleaned.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deadbeef::testing;
+
+    fn clean(presets: &str, text: &str) -> String {
+        Cleanup(preset_rules(presets)).apply(text)
+    }
+
+    #[test]
+    fn remaster_preset() {
+        let _api = testing::setup();
+
+        assert_eq!(clean("remaster", "Help! (Remastered 2009)"), "Help!");
+        assert_eq!(clean("remaster", "Heroes [2017 Remaster]"), "Heroes");
+        assert_eq!(
+            clean("remaster", "Wish You Were Here - 2011 Remastered Version"),
+            "Wish You Were Here"
+        );
+        assert_eq!(clean("remaster", "Remaster Me"), "Remaster Me");
+    }
+
+    #[test]
+    fn explicit_preset() {
+        let _api = testing::setup();
+
+        assert_eq!(clean("explicit", "Song (Explicit)"), "Song");
+        assert_eq!(clean("explicit", "Song [Clean Version]"), "Song");
+        assert_eq!(clean("explicit", "Explicit Content"), "Explicit Content");
+    }
+
+    #[test]
+    fn feat_preset() {
+        let _api = testing::setup();
+
+        assert_eq!(clean("feat", "Song (feat. Someone)"), "Song");
+        assert_eq!(clean("feat", "Song [ft. A & B]"), "Song");
+        assert_eq!(clean("feat", "Artist featuring Someone Else"), "Artist");
+        assert_eq!(clean("feat", "Defeat"), "Defeat");
+    }
+
+    #[test]
+    fn live_preset() {
+        let _api = testing::setup();
+
+        assert_eq!(clean("live", "Song (Live at Wembley)"), "Song");
+        assert_eq!(clean("live", "Song - Live in Tokyo 1998"), "Song");
+        assert_eq!(clean("live", "Alive"), "Alive");
+        // Nothing would be left, so the field stays as it is.
+        assert_eq!(clean("live", "(Live)"), "(Live)");
+    }
+
+    #[test]
+    fn brackets_preset() {
+        let _api = testing::setup();
+
+        assert_eq!(clean("brackets", "Song (Bonus Track) [Demo]"), "Song");
+        assert_eq!(clean("brackets", "(Intro) Song"), "(Intro) Song");
+    }
+
+    #[test]
+    fn unknown_presets_are_ignored() {
+        let _api = testing::setup();
+
+        assert_eq!(clean(" Live , nope,,", "Song (Live)"), "Song");
+    }
+
+    #[test]
+    fn musicbrainz_queries_keep_their_quotes() {
+        let _api = testing::setup();
+        let presets = "remaster,explicit,feat,live,brackets";
+
+        assert_eq!(
+            clean(
+                presets,
+                r#"release:"Abbey Road (Remastered 2009)" AND artist:"The Beatles feat. Billy Preston""#
+            ),
+            r#"release:"Abbey Road" AND artist:"The Beatles""#
+        );
+        assert_eq!(
+            clean(
+                presets,
+                r#"release:"Alive - Live at Budokan" AND artist:"Cheap Trick""#
+            ),
+            r#"release:"Alive" AND artist:"Cheap Trick""#
+        );
+        assert_eq!(
+            clean(
+                presets,
+                r#"release:"Songs [Deluxe]" AND artist:"Band (US)""#
+            ),
+            r#"release:"Songs" AND artist:"Band""#
+        );
+    }
+
+    #[test]
+    fn musicbrainz_queries_skip_escaped_quotes() {
+        let _api = testing::setup();
+
+        assert_eq!(
+            clean(
+                "live",
+                r#"release:"Songs - Live at \"The Forum\"" AND artist:"Band""#
+            ),
+            r#"release:"Songs" AND artist:"Band""#
+        );
+        assert_eq!(
+            clean(
+                "feat",
+                r#"release:"Album" AND artist:"Band feat. \"Lil\" Someone""#
+            ),
+            r#"release:"Album" AND artist:"Band""#
+        );
+        assert_eq!(
+            clean("remaster", r#"release:"Say \"Hi\" (2015 Remaster)""#),
+            r#"release:"Say \"Hi\"""#
+        );
+    }
+
+    #[test]
+    fn custom_rules_only_apply_to_their_section() {
+        let _api = testing::setup();
+        let content = "\
+# comment
+[presence]
+Foo => Bar
+[musicbrainz]
+\\s*\\(Disc \\d+\\) =>
+";
+
+        let presence = Cleanup(custom_rules(content, CleanupTarget::Presence));
+        let query = Cleanup(custom_rules(content, CleanupTarget::MusicBrainz));
+
+        assert_eq!(presence.apply("Foo (Disc 2)"), "Bar (Disc 2)");
+        assert_eq!(query.apply("Foo (Disc 2)"), "Foo");
+    }
+}
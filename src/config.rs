@@ -16,13 +16,58 @@ pub const PLUGIN_COPYRIGHT: &CStr = unsafe {
 pub const PLUGIN_SETTING_DLG: &CStr = cr#"
 property "Enable" checkbox discordrpc.enable 1;
 property "Client ID" entry discordrpc.client_id "1440255782418387026";
+property "Discord IPC socket (empty = first found, 0-9 = index, or a path)" entry discordrpc.ipc_socket "";
+property "Send presence to every running Discord client" checkbox discordrpc.ipc_broadcast 0;
+property "Dry run (log the activity instead of sending it to Discord)" checkbox discordrpc.dry_run 0;
+property "Dry run output file (JSON lines, empty = log only)" entry discordrpc.dry_run_file "";
 property "Title format" entry discordrpc.title_script "%title%$if(%ispaused%,' ('paused')')";
 property "State format" entry discordrpc.state_script "%artist%";
 property "Display time" select[2] discord_presence.end_timestamp2 1 "Only elapsed time" "Full track time";
 property "Hide on pause" checkbox discordrpc.hide_on_pause 0;
+property "Clear presence when paused longer than (minutes, 0 = never)" spinbtn[0,1440,1] discordrpc.pause_timeout 0;
+property "Show idle presence when stopped" checkbox discordrpc.idle_enable 0;
+property "Idle text" entry discordrpc.idle_text "Idle in DeaDBeeF";
+property "Show last played track when idle" checkbox discordrpc.idle_last_track 1;
+property "Idle image asset" entry discordrpc.idle_image "default";
+property "Clear idle presence after (minutes, 0 = never)" spinbtn[0,1440,1] discordrpc.idle_timeout 10;
+property "Activity type" select[4] discordrpc.activity_type 0 "Listening" "Playing" "Watching" "Competing";
+property "Member list shows" select[3] discordrpc.status_display 0 "App name" "Artist (state)" "Title (details)";
 property "Icon text format" entry discordrpc.icon_script "%album%";
-property "Display cover from" select[2] discordrpc.cover_source 1 "No cover" "MusicBrainz";
+property "Show position as party size" select[3] discordrpc.party_mode 0 "Off" "Track number in album" "Position in playlist";
+property "Romanize title" select[3] discordrpc.romanize_details 0 "Original" "Romanized" "Original (romanized)";
+property "Romanize state" select[3] discordrpc.romanize_state 0 "Original" "Romanized" "Original (romanized)";
+property "Romanize icon text" select[3] discordrpc.romanize_icon_text 0 "Original" "Romanized" "Original (romanized)";
+property "Romanize using" select[2] discordrpc.romanize_source 0 "Built-in transliteration (Han read as Mandarin)" "MusicBrainz Latin names, then transliteration";
+property "Display cover from" select[4] discordrpc.cover_source 1 "No cover" "MusicBrainz" "Local artwork" "Local artwork, then MusicBrainz";
+property "Cover size" select[4] discordrpc.cover_size 1 "250 px" "500 px" "1200 px" "Original";
+property "Prefetch the next track's cover" checkbox discordrpc.prefetch_cover 1;
+property "Publish local covers via" select[3] discordrpc.upload_method 0 "Disabled" "HTTP upload (needs curl)" "Synced directory";
+property "Upload endpoint URL" entry discordrpc.upload_url "";
+property "Upload form field" entry discordrpc.upload_field "file";
+property "Upload extra header" entry discordrpc.upload_header "";
+property "Upload response URL JSON path" entry discordrpc.upload_json_path "url";
+property "Synced directory" entry discordrpc.sync_dir "";
+property "Synced directory base URL" entry discordrpc.sync_base_url "";
+property "Export now playing for overlays" checkbox discordrpc.export_enable 0;
+property "Export JSON file (empty = off)" entry discordrpc.export_json_file "nowplaying.json";
+property "Export JSON text format" entry discordrpc.export_json_script "%artist% - %title%";
+property "Export text file (empty = off)" entry discordrpc.export_text_file "nowplaying.txt";
+property "Export text format" entry discordrpc.export_text_script "%artist% - %title%";
+property "Serve now playing on localhost (/nowplaying, /cover, /events)" checkbox discordrpc.server_enable 0;
+property "Now playing server port" spinbtn[1024,65535,1] discordrpc.server_port 6474;
+property "Now playing server allowed origin (empty = none)" entry discordrpc.server_allow_origin "";
+property "Webhook URL (empty = off, needs curl)" entry discordrpc.webhook_url "";
+property "Webhook headers (Name: value, separated by |)" entry discordrpc.webhook_headers "";
+property "ListenBrainz user token (empty = off, needs curl)" password discordrpc.listenbrainz_token "";
+property "ListenBrainz server URL" entry discordrpc.listenbrainz_url "https://api.listenbrainz.org";
+property "Show synced lyrics as state (.lrc file or SYNCEDLYRICS/LYRICS tag)" checkbox discordrpc.lyrics_enable 0;
+property "Rotate state through formats (separated by |, next: for the next track)" entry discordrpc.state_rotation "";
+property "Rotate state every (seconds)" spinbtn[4,600,1] discordrpc.state_rotation_interval 15;
+property "Log level" select[4] discordrpc.log_level 1 "Error" "Warning" "Info" "Debug";
+property "Write log file (discordrpc/discordrpc.log in the config directory)" checkbox discordrpc.log_file 0;
 property "MusicBrainz album query format" entry discorrpc.query_album_script "release:\"%album%\" AND artist:\"%artist%\"";
+property "Presence cleanup presets (remaster, explicit, feat, live, brackets)" entry discordrpc.cleanup_presence "";
+property "MusicBrainz query cleanup presets" entry discordrpc.cleanup_query "";
 "#;
 
 pub static PLUGIN: LazyLock<SafeDBMisc> = LazyLock::new(|| {
@@ -47,6 +92,7 @@ pub static PLUGIN: LazyLock<SafeDBMisc> = LazyLock::new(|| {
     plugin.plugin.start = Some(crate::start);
     plugin.plugin.stop = Some(crate::stop);
     plugin.plugin.message = Some(crate::message);
+    plugin.plugin.get_actions = Some(crate::get_actions);
 
     SafeDBMisc(plugin)
 });
@@ -58,32 +104,125 @@ pub struct ConfigDefault;
 impl ConfigKey {
     pub const ENABLE: *const i8 = c"discordrpc.enable".as_ptr();
     pub const CLIENT_ID: *const i8 = c"discordrpc.client_id".as_ptr();
+    pub const IPC_SOCKET: *const i8 = c"discordrpc.ipc_socket".as_ptr();
+    pub const IPC_BROADCAST: *const i8 = c"discordrpc.ipc_broadcast".as_ptr();
+    pub const DRY_RUN: *const i8 = c"discordrpc.dry_run".as_ptr();
+    pub const DRY_RUN_FILE: *const i8 = c"discordrpc.dry_run_file".as_ptr();
     pub const TITLE_SCRIPT: *const i8 = c"discordrpc.title_script".as_ptr();
     pub const STATE_SCRIPT: *const i8 = c"discordrpc.state_script".as_ptr();
     pub const END_TIMESTAMP2: *const i8 = c"discord_presence.end_timestamp2".as_ptr();
     pub const ICON_SCRIPT: *const i8 = c"discordrpc.icon_script".as_ptr();
+    pub const ACTIVITY_TYPE: *const i8 = c"discordrpc.activity_type".as_ptr();
+    pub const STATUS_DISPLAY: *const i8 = c"discordrpc.status_display".as_ptr();
+    pub const PARTY_MODE: *const i8 = c"discordrpc.party_mode".as_ptr();
+    pub const ROMANIZE_DETAILS: *const i8 = c"discordrpc.romanize_details".as_ptr();
+    pub const ROMANIZE_STATE: *const i8 = c"discordrpc.romanize_state".as_ptr();
+    pub const ROMANIZE_ICON_TEXT: *const i8 = c"discordrpc.romanize_icon_text".as_ptr();
+    pub const ROMANIZATION_SOURCE: *const i8 = c"discordrpc.romanize_source".as_ptr();
     pub const COVER_SOURCE: *const i8 = c"discordrpc.cover_source".as_ptr();
     pub const QUERY_ALBUM_SCRIPT: *const i8 = c"discorrpc.query_album_script".as_ptr();
+    pub const CLEANUP_PRESENCE_PRESETS: *const i8 = c"discordrpc.cleanup_presence".as_ptr();
+    pub const CLEANUP_QUERY_PRESETS: *const i8 = c"discordrpc.cleanup_query".as_ptr();
     pub const HIDE_ON_PAUSE: *const i8 = c"discordrpc.hide_on_pause".as_ptr();
+    pub const PAUSE_TIMEOUT: *const i8 = c"discordrpc.pause_timeout".as_ptr();
+    pub const IDLE_ENABLE: *const i8 = c"discordrpc.idle_enable".as_ptr();
+    pub const IDLE_TEXT: *const i8 = c"discordrpc.idle_text".as_ptr();
+    pub const IDLE_LAST_TRACK: *const i8 = c"discordrpc.idle_last_track".as_ptr();
+    pub const IDLE_IMAGE: *const i8 = c"discordrpc.idle_image".as_ptr();
+    pub const IDLE_TIMEOUT: *const i8 = c"discordrpc.idle_timeout".as_ptr();
+    pub const EXPORT_ENABLE: *const i8 = c"discordrpc.export_enable".as_ptr();
+    pub const EXPORT_JSON_FILE: *const i8 = c"discordrpc.export_json_file".as_ptr();
+    pub const EXPORT_JSON_SCRIPT: *const i8 = c"discordrpc.export_json_script".as_ptr();
+    pub const EXPORT_TEXT_FILE: *const i8 = c"discordrpc.export_text_file".as_ptr();
+    pub const EXPORT_TEXT_SCRIPT: *const i8 = c"discordrpc.export_text_script".as_ptr();
+    pub const SERVER_ENABLE: *const i8 = c"discordrpc.server_enable".as_ptr();
+    pub const SERVER_PORT: *const i8 = c"discordrpc.server_port".as_ptr();
+    pub const SERVER_ALLOW_ORIGIN: *const i8 = c"discordrpc.server_allow_origin".as_ptr();
+    pub const WEBHOOK_URL: *const i8 = c"discordrpc.webhook_url".as_ptr();
+    pub const WEBHOOK_HEADERS: *const i8 = c"discordrpc.webhook_headers".as_ptr();
+    pub const LISTENBRAINZ_TOKEN: *const i8 = c"discordrpc.listenbrainz_token".as_ptr();
+    pub const LISTENBRAINZ_URL: *const i8 = c"discordrpc.listenbrainz_url".as_ptr();
+    pub const LYRICS_ENABLE: *const i8 = c"discordrpc.lyrics_enable".as_ptr();
+    pub const STATE_ROTATION: *const i8 = c"discordrpc.state_rotation".as_ptr();
+    pub const STATE_ROTATION_INTERVAL: *const i8 = c"discordrpc.state_rotation_interval".as_ptr();
+    pub const LOG_LEVEL: *const i8 = c"discordrpc.log_level".as_ptr();
+    pub const LOG_FILE: *const i8 = c"discordrpc.log_file".as_ptr();
+    pub const COVER_SIZE: *const i8 = c"discordrpc.cover_size".as_ptr();
+    pub const PREFETCH_COVER: *const i8 = c"discordrpc.prefetch_cover".as_ptr();
+    pub const UPLOAD_METHOD: *const i8 = c"discordrpc.upload_method".as_ptr();
+    pub const UPLOAD_URL: *const i8 = c"discordrpc.upload_url".as_ptr();
+    pub const UPLOAD_FIELD: *const i8 = c"discordrpc.upload_field".as_ptr();
+    pub const UPLOAD_HEADER: *const i8 = c"discordrpc.upload_header".as_ptr();
+    pub const UPLOAD_JSON_PATH: *const i8 = c"discordrpc.upload_json_path".as_ptr();
+    pub const SYNC_DIR: *const i8 = c"discordrpc.sync_dir".as_ptr();
+    pub const SYNC_BASE_URL: *const i8 = c"discordrpc.sync_base_url".as_ptr();
 }
 
 impl ConfigDefault {
     pub const ENABLE: i32 = 1;
     pub const CLIENT_ID: *const i8 = c"1440255782418387026".as_ptr();
+    pub const IPC_SOCKET: *const i8 = c"".as_ptr();
+    pub const IPC_BROADCAST: i32 = 0;
+    pub const DRY_RUN: i32 = 0;
+    pub const DRY_RUN_FILE: *const i8 = c"".as_ptr();
     pub const TITLE_SCRIPT: *const i8 = c"%title%$if(%ispaused%,' ('paused')')".as_ptr();
     pub const STATE_SCRIPT: *const i8 = c"%artist%".as_ptr();
     pub const END_TIMESTAMP2: i32 = 1;
     pub const ICON_SCRIPT: *const i8 = c"%album%".as_ptr();
+    pub const ACTIVITY_TYPE: i32 = ActivityKind::Listening as i32;
+    pub const STATUS_DISPLAY: i32 = StatusDisplay::Name as i32;
+    pub const PARTY_MODE: i32 = PartyMode::Off as i32;
+    pub const ROMANIZE_DETAILS: i32 = Romanization::Original as i32;
+    pub const ROMANIZE_STATE: i32 = Romanization::Original as i32;
+    pub const ROMANIZE_ICON_TEXT: i32 = Romanization::Original as i32;
+    pub const ROMANIZATION_SOURCE: i32 = RomanizationSource::Builtin as i32;
     pub const COVER_SOURCE: i32 = CoverSource::MusicBrainz as i32;
     pub const QUERY_ALBUM_SCRIPT: *const i8 =
         cr#"release:\"%album%\" AND artist:\"%artist%\""#.as_ptr();
+    pub const CLEANUP_PRESENCE_PRESETS: *const i8 = c"".as_ptr();
+    pub const CLEANUP_QUERY_PRESETS: *const i8 = c"".as_ptr();
     pub const HIDE_ON_PAUSE: i32 = 0;
+    pub const PAUSE_TIMEOUT: i32 = 0;
+    pub const IDLE_ENABLE: i32 = 0;
+    pub const IDLE_TEXT: *const i8 = c"Idle in DeaDBeeF".as_ptr();
+    pub const IDLE_LAST_TRACK: i32 = 1;
+    pub const IDLE_IMAGE: *const i8 = c"default".as_ptr();
+    pub const IDLE_TIMEOUT: i32 = 10;
+    pub const EXPORT_ENABLE: i32 = 0;
+    pub const EXPORT_JSON_FILE: *const i8 = c"nowplaying.json".as_ptr();
+    pub const EXPORT_JSON_SCRIPT: *const i8 = c"%artist% - %title%".as_ptr();
+    pub const EXPORT_TEXT_FILE: *const i8 = c"nowplaying.txt".as_ptr();
+    pub const EXPORT_TEXT_SCRIPT: *const i8 = c"%artist% - %title%".as_ptr();
+    pub const SERVER_ENABLE: i32 = 0;
+    pub const SERVER_PORT: i32 = 6474;
+    pub const SERVER_ALLOW_ORIGIN: *const i8 = c"".as_ptr();
+    pub const WEBHOOK_URL: *const i8 = c"".as_ptr();
+    pub const WEBHOOK_HEADERS: *const i8 = c"".as_ptr();
+    pub const LISTENBRAINZ_TOKEN: *const i8 = c"".as_ptr();
+    pub const LISTENBRAINZ_URL: *const i8 = c"https://api.listenbrainz.org".as_ptr();
+    pub const LYRICS_ENABLE: i32 = 0;
+    pub const STATE_ROTATION: *const i8 = c"".as_ptr();
+    pub const STATE_ROTATION_INTERVAL: i32 = 15;
+    pub const LOG_LEVEL: i32 = LogLevel::Warn as i32;
+    pub const LOG_FILE: i32 = 0;
+    pub const COVER_SIZE: i32 = CoverSize::Px500 as i32;
+    pub const PREFETCH_COVER: i32 = 1;
+    pub const UPLOAD_METHOD: i32 = UploadMethod::Disabled as i32;
+    pub const UPLOAD_URL: *const i8 = c"".as_ptr();
+    pub const UPLOAD_FIELD: *const i8 = c"file".as_ptr();
+    pub const UPLOAD_HEADER: *const i8 = c"".as_ptr();
+    pub const UPLOAD_JSON_PATH: *const i8 = c"url".as_ptr();
+    pub const SYNC_DIR: *const i8 = c"".as_ptr();
+    pub const SYNC_BASE_URL: *const i8 = c"".as_ptr();
 }
 
 #[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CoverSource {
     NoCover = 0,
     MusicBrainz = 1,
+    Local = 2,
+    LocalThenMusicBrainz = 3,
 }
 
 impl TryFrom<i32> for CoverSource {
@@ -93,10 +232,211 @@ impl TryFrom<i32> for CoverSource {
         match value {
             0 => Ok(CoverSource::NoCover),
             1 => Ok(CoverSource::MusicBrainz),
+            2 => Ok(CoverSource::Local),
+            3 => Ok(CoverSource::LocalThenMusicBrainz),
             _ => Err(Error::InvalidCoverSource),
         }
     }
 }
 
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoverSize {
+    Px250 = 0,
+    Px500 = 1,
+    Px1200 = 2,
+    Original = 3,
+}
+
+impl CoverSize {
+    /// Suffix of the Cover Art Archive front image URL for this size.
+    pub fn caa_suffix(self) -> &'static str {
+        match self {
+            CoverSize::Px250 => "front-250",
+            CoverSize::Px500 => "front-500",
+            CoverSize::Px1200 => "front-1200",
+            CoverSize::Original => "front",
+        }
+    }
+
+    /// Longest edge local covers are scaled down to, `None` to keep the original size.
+    pub fn max_pixels(self) -> Option<u32> {
+        match self {
+            CoverSize::Px250 => Some(250),
+            CoverSize::Px500 => Some(500),
+            CoverSize::Px1200 => Some(1200),
+            CoverSize::Original => None,
+        }
+    }
+}
+
+impl TryFrom<i32> for CoverSize {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self> {
+        match value {
+            0 => Ok(CoverSize::Px250),
+            1 => Ok(CoverSize::Px500),
+            2 => Ok(CoverSize::Px1200),
+            3 => Ok(CoverSize::Original),
+            _ => Err(Error::InvalidCoverSize),
+        }
+    }
+}
+
+#[repr(i32)]
+pub enum UploadMethod {
+    Disabled = 0,
+    Http = 1,
+    SyncedDirectory = 2,
+}
+
+impl TryFrom<i32> for UploadMethod {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self> {
+        match value {
+            0 => Ok(UploadMethod::Disabled),
+            1 => Ok(UploadMethod::Http),
+            2 => Ok(UploadMethod::SyncedDirectory),
+            _ => Err(Error::InvalidUploadMethod),
+        }
+    }
+}
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl TryFrom<i32> for LogLevel {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self> {
+        match value {
+            0 => Ok(LogLevel::Error),
+            1 => Ok(LogLevel::Warn),
+            2 => Ok(LogLevel::Info),
+            3 => Ok(LogLevel::Debug),
+            _ => Err(Error::InvalidLogLevel),
+        }
+    }
+}
+
+/// Verb Discord shows in front of the presence, e.g. "Listening to".
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActivityKind {
+    Listening = 0,
+    Playing = 1,
+    Watching = 2,
+    Competing = 3,
+}
+
+impl TryFrom<i32> for ActivityKind {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self> {
+        match value {
+            0 => Ok(ActivityKind::Listening),
+            1 => Ok(ActivityKind::Playing),
+            2 => Ok(ActivityKind::Watching),
+            3 => Ok(ActivityKind::Competing),
+            _ => Err(Error::InvalidActivityKind),
+        }
+    }
+}
+
+/// Which field the member list shows after the activity verb.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusDisplay {
+    Name = 0,
+    State = 1,
+    Details = 2,
+}
+
+impl TryFrom<i32> for StatusDisplay {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self> {
+        match value {
+            0 => Ok(StatusDisplay::Name),
+            1 => Ok(StatusDisplay::State),
+            2 => Ok(StatusDisplay::Details),
+            _ => Err(Error::InvalidStatusDisplay),
+        }
+    }
+}
+
+/// What the "(3 of 12)" party size counts.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartyMode {
+    Off = 0,
+    Album = 1,
+    Playlist = 2,
+}
+
+impl TryFrom<i32> for PartyMode {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self> {
+        match value {
+            0 => Ok(PartyMode::Off),
+            1 => Ok(PartyMode::Album),
+            2 => Ok(PartyMode::Playlist),
+            _ => Err(Error::InvalidPartyMode),
+        }
+    }
+}
+
+/// How a field with non-Latin text is shown.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Romanization {
+    Original = 0,
+    Romanized = 1,
+    /// "Original (romanized)".
+    Both = 2,
+}
+
+impl TryFrom<i32> for Romanization {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self> {
+        match value {
+            0 => Ok(Romanization::Original),
+            1 => Ok(Romanization::Romanized),
+            2 => Ok(Romanization::Both),
+            _ => Err(Error::InvalidRomanization),
+        }
+    }
+}
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RomanizationSource {
+    Builtin = 0,
+    /// Latin names of the resolved MusicBrainz release, transliterating whatever they miss.
+    MusicBrainz = 1,
+}
+
+impl TryFrom<i32> for RomanizationSource {
+    type Error = Error;
+
+    fn try_from(value: i32) -> Result<Self> {
+        match value {
+            0 => Ok(RomanizationSource::Builtin),
+            1 => Ok(RomanizationSource::MusicBrainz),
+            _ => Err(Error::InvalidRomanization),
+        }
+    }
+}
+
 unsafe impl Sync for SafeDBMisc {}
 unsafe impl Send for SafeDBMisc {}
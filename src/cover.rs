@@ -0,0 +1,313 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    API,
+    artwork::local_cover,
+    cleanup::{Cleanup, CleanupTarget},
+    config::{ConfigDefault, ConfigKey, CoverSize, CoverSource},
+    deadbeef::{
+        PL_MAIN, ddb_shuffle_e_DDB_SHUFFLE_ALBUMS, ddb_shuffle_e_DDB_SHUFFLE_OFF,
+        safe_wrapper::{SafeDBPlayItem, SafeDBPlayList},
+    },
+    error::{Error, Result},
+    musicbrainz::get_album_cover_url_from_query,
+    overrides::{cover_override, tag_overrides},
+    profile::select_profile,
+    status,
+    upload::publish_cover,
+    util::{MutexExt, format_string},
+    worker,
+};
+
+/// Discord rejects image URLs (and asset keys) longer than this.
+const MAX_IMAGE_URL_LEN: usize = 256;
+/// Identifies an album for caching; the directory keeps same-named compilations apart.
+const COVER_KEY_SCRIPT: &str = "%album artist%|%album%|%directoryname%";
+const MAX_CACHED_COVERS: usize = 512;
+/// How long to wait for a lookup another worker started before giving up on the cover.
+const MAX_PENDING_WAIT: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref COVER_CACHE: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+    static ref PENDING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Everything needed to look up the cover of one item, captured up front so the lookup can
+/// run after the item stopped playing.
+pub struct CoverRequest {
+    pub key: String,
+    source: CoverSource,
+    size: CoverSize,
+    album_query: String,
+    item: SafeDBPlayItem,
+}
+
+impl CoverRequest {
+    pub fn new(item: SafeDBPlayItem, plt: &SafeDBPlayList, source: CoverSource) -> Result<Self> {
+        let api = API.get().unwrap();
+        let size = CoverSize::try_from(
+            api.conf_get_int(ConfigKey::COVER_SIZE, ConfigDefault::COVER_SIZE)?,
+        )?;
+        let album_query_script = api.conf_get_str(
+            ConfigKey::QUERY_ALBUM_SCRIPT,
+            ConfigDefault::QUERY_ALBUM_SCRIPT,
+        )?;
+        let album_query = Cleanup::load(CleanupTarget::MusicBrainz)?.apply(&format_string(
+            &item,
+            plt,
+            &album_query_script,
+        )?);
+        let key = format!(
+            "{}|{}|{}",
+            source as i32,
+            size as i32,
+            format_string(&item, plt, COVER_KEY_SCRIPT)?
+        );
+
+        Ok(Self {
+            key,
+            source,
+            size,
+            album_query,
+            item,
+        })
+    }
+
+    /// The cleaned MusicBrainz album query for the item.
+    pub fn album_query(&self) -> &str {
+        &self.album_query
+    }
+
+    /// Cover known without any lookup, from the cache or because covers are disabled.
+    pub fn cached(&self) -> Option<String> {
+        if let CoverSource::NoCover = self.source {
+            return Some("default".to_string());
+        }
+
+        COVER_CACHE.lock_recover().get(&self.key).cloned()
+    }
+
+    /// Looks the cover up and caches it. If another worker, like the prefetch, is already on
+    /// it, waits for its result instead. Returns `None` if the lookup was interrupted or hung.
+    pub fn resolve(&self) -> Option<String> {
+        loop {
+            if let Some(cover) = self.cached() {
+                return Some(cover);
+            }
+
+            if PENDING.lock_recover().insert(self.key.clone()) {
+                break;
+            }
+
+            // Waits for the other lookup to finish, then tries the cache again.
+            let timed_out = worker::sleep_while(MAX_PENDING_WAIT, || {
+                PENDING.lock_recover().contains(&self.key)
+            });
+
+            if timed_out || worker::is_cancelled() {
+                return None;
+            }
+        }
+
+        let cover = match self.source {
+            CoverSource::MusicBrainz => self.musicbrainz_cover(),
+            CoverSource::Local => self.local_cover_url(),
+            CoverSource::LocalThenMusicBrainz => {
+                self.local_cover_url().or_else(|_| self.musicbrainz_cover())
+            }
+            CoverSource::NoCover => Ok("default".to_string()),
+        };
+        let cover = match cover {
+            // An interrupted lookup says nothing about the album, so leave it uncached.
+            Err(Error::Cancelled) => {
+                PENDING.lock_recover().remove(&self.key);
+                return None;
+            }
+            // Only a definitive miss is remembered; network and upload failures are retried
+            // the next time the album plays.
+            Err(e @ (Error::MusicbrainzNoReleaseFound | Error::ArtworkNotFound)) => {
+                status::record_error("cover", &e);
+                "default".to_string()
+            }
+            Err(e) => {
+                status::record_error("cover", &e);
+                PENDING.lock_recover().remove(&self.key);
+                return Some("default".to_string());
+            }
+            Ok(cover) => cover,
+        };
+
+        let mut cache = COVER_CACHE.lock_recover();
+
+        if cache.len() >= MAX_CACHED_COVERS {
+            cache.clear();
+        }
+        cache.insert(self.key.clone(), cover.clone());
+        PENDING.lock_recover().remove(&self.key);
+
+        Some(cover)
+    }
+
+    fn musicbrainz_cover(&self) -> Result<String> {
+        get_album_cover_url_from_query(&self.album_query, self.size)
+    }
+
+    /// Publishes the item's embedded or folder cover and returns its public URL.
+    fn local_cover_url(&self) -> Result<String> {
+        let api = API.get().unwrap();
+        let cover = local_cover(&self.item)?;
+
+        publish_cover(&cover, self.size.max_pixels()).inspect_err(|e| {
+            api.log_warn(format!("Failed to publish local cover: {:?}", e));
+        })
+    }
+}
+
+/// Number of cached covers and of lookups in progress.
+pub fn cache_stats() -> (usize, usize) {
+    (
+        COVER_CACHE.lock_recover().len(),
+        PENDING.lock_recover().len(),
+    )
+}
+
+/// The item DeaDBeeF will most likely play after the current one: the head of the play
+/// queue, otherwise the next playlist entry unless tracks are shuffled.
+pub fn next_item() -> Result<Option<SafeDBPlayItem>> {
+    let api = API.get().unwrap();
+
+    if api.playqueue_get_count()? > 0 {
+        let item = api.playqueue_get_item(0)?;
+
+        return Ok((!item.is_null()).then_some(item));
+    }
+
+    let shuffle = api.streamer_get_shuffle()?;
+
+    if shuffle != ddb_shuffle_e_DDB_SHUFFLE_OFF && shuffle != ddb_shuffle_e_DDB_SHUFFLE_ALBUMS {
+        return Ok(None);
+    }
+
+    let nowplaying = api.streamer_get_playing_track()?;
+
+    if nowplaying.is_null() {
+        return Ok(None);
+    }
+
+    let item = api.pl_get_next(&nowplaying, PL_MAIN)?;
+
+    Ok((!item.is_null()).then_some(item))
+}
+
+/// Resolves the next track's cover ahead of time so it shows up as soon as the track starts.
+pub fn prefetch_next_cover() -> Result<()> {
+    let api = API.get().unwrap();
+
+    if api.conf_get_int(ConfigKey::PREFETCH_COVER, ConfigDefault::PREFETCH_COVER)? == 0 {
+        return Ok(());
+    }
+
+    let Some(item) = next_item()? else {
+        return Ok(());
+    };
+
+    let plt = api.plt_get_curr()?;
+    let tags = tag_overrides(&item)?;
+
+    // Hidden tracks must not reach the network, and overridden covers need no lookup.
+    if tags.hide || tags.cover.is_some() || cover_override(&item, &plt)?.is_some() {
+        return Ok(());
+    }
+
+    let profile = select_profile(&item, &plt)?;
+    let request = CoverRequest::new(item, &plt, profile.cover_source)?;
+
+    // Also skips the lookup when the next track is on the album that is playing now.
+    if request.cached().is_some() {
+        return Ok(());
+    }
+
+    api.log_debug(format!("Prefetching cover for {}", request.key));
+    request.resolve();
+
+    Ok(())
+}
+
+/// Falls back to the default asset for image URLs Discord would reject.
+pub fn validate_large_image(large_image: String) -> String {
+    let is_url = large_image.contains("://");
+
+    if large_image.len() > MAX_IMAGE_URL_LEN {
+        API.get().unwrap().log_warn(format!(
+            "Cover '{}' is longer than {} characters, using the default image.",
+            large_image, MAX_IMAGE_URL_LEN
+        ));
+        "default".to_string()
+    } else if is_url && !large_image.starts_with("https://") {
+        API.get().unwrap().log_warn(format!(
+            "Cover '{}' is not an https URL, using the default image.",
+            large_image
+        ));
+        "default".to_string()
+    } else {
+        large_image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ptr, thread};
+
+    use super::*;
+    use crate::deadbeef::testing;
+
+    fn request(key: &str, source: CoverSource) -> CoverRequest {
+        CoverRequest {
+            key: key.to_string(),
+            source,
+            size: CoverSize::Px250,
+            album_query: String::new(),
+            item: SafeDBPlayItem::new(ptr::null_mut()),
+        }
+    }
+
+    #[test]
+    fn resolve_waits_for_a_pending_lookup() {
+        let _api = testing::setup();
+        let request = request("pending", CoverSource::MusicBrainz);
+
+        PENDING.lock_recover().insert(request.key.clone());
+
+        let prefetch = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(300));
+            COVER_CACHE.lock_recover().insert(
+                "pending".to_string(),
+                "https://example.com/cover.jpg".to_string(),
+            );
+            PENDING.lock_recover().remove("pending");
+        });
+
+        assert_eq!(
+            request.resolve().as_deref(),
+            Some("https://example.com/cover.jpg")
+        );
+        prefetch.join().unwrap();
+    }
+
+    #[test]
+    fn failed_lookups_are_not_cached() {
+        let _api = testing::setup();
+        // There is no artwork plugin to ask, which says nothing about the album itself.
+        let request = request("failed", CoverSource::Local);
+
+        assert_eq!(request.resolve().as_deref(), Some("default"));
+        assert_eq!(request.cached(), None);
+        assert!(!PENDING.lock_recover().contains("failed"));
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    path::PathBuf,
+    process::{self, Child, Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    API,
+    error::{Error, Result},
+    worker,
+};
+
+/// How often a running request checks whether the plugin is stopping.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a request may keep running once the plugin is stopping, so a last update still
+/// goes out but nothing outlives the shutdown timeout.
+const CANCEL_GRACE: Duration = Duration::from_secs(2);
+
+/// Numbers the config files of concurrent requests.
+static CONFIG_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// Set once the missing `curl` executable has been reported, so it isn't on every request.
+static CURL_MISSING_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// A curl config file only the user can read, removed once the request is done.
+///
+/// The URL and headers go through it rather than the command line, which other users can see
+/// in the process list, since both may carry tokens.
+struct ConfigFile(PathBuf);
+
+impl ConfigFile {
+    fn create(url: &str, headers: &[String]) -> Result<Self> {
+        let path = env::temp_dir().join(format!(
+            "discordrpc-curl-{}-{}.conf",
+            process::id(),
+            CONFIG_FILE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let mut options = OpenOptions::new();
+
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(&path).map_err(Error::Io)?;
+        let config = Self(path);
+        let mut content = format!("url = {}\n", quote(url));
+
+        for header in headers {
+            content.push_str(&format!("header = {}\n", quote(header)));
+        }
+        file.write_all(content.as_bytes()).map_err(Error::Io)?;
+
+        Ok(config)
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Quotes `value` for a curl config file; a line break would end the option early.
+fn quote(value: &str) -> String {
+    let value = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(['\r', '\n'], " ");
+
+    format!("\"{}\"", value)
+}
+
+/// DeaDBeeF's VFS can only read, so requests with a body go through the `curl` executable.
+pub enum Body<'a> {
+    Json(&'a str),
+    File {
+        field: &'a str,
+        file_name: &'a str,
+        data: &'a [u8],
+    },
+}
+
+/// POSTs `body` to `url` with extra `headers` ("Name: value") and returns the response body.
+pub fn post(url: &str, headers: &[String], body: Body) -> Result<String> {
+    let mut command = Command::new("curl");
+    let mut headers = headers.to_vec();
+
+    command.args(["--silent", "--show-error", "--fail", "--max-time", "30"]);
+
+    let stdin = match body {
+        Body::Json(json) => {
+            headers.push("Content-Type: application/json".to_string());
+            command.args(["--data-binary", "@-"]);
+            json.as_bytes()
+        }
+        Body::File {
+            field,
+            file_name,
+            data,
+        } => {
+            command
+                .arg("--form")
+                .arg(format!("{}=@-;filename={}", field, file_name));
+            data
+        }
+    };
+
+    let config = ConfigFile::create(url, &headers)?;
+
+    command
+        .arg("--config")
+        .arg(&config.0)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+
+        const CREATE_NO_WINDOW: u32 = 0x08000000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+
+    let mut child = command.spawn().map_err(|e| {
+        if e.kind() == ErrorKind::NotFound && !CURL_MISSING_WARNED.swap(true, Ordering::SeqCst) {
+            API.get().unwrap().log_warn(
+                "curl was not found on PATH; cover uploads, the webhook and ListenBrainz need it."
+                    .to_string(),
+            );
+        }
+        Error::Io(e)
+    })?;
+    let child_stdin = child.stdin.take();
+    let child_stdout = child.stdout.take();
+    let child_stderr = child.stderr.take();
+
+    // The pipes are served from their own threads so waiting for curl can be cut short.
+    let (status, stdout, stderr) = thread::scope(|scope| {
+        scope.spawn(move || child_stdin.map(|mut pipe| pipe.write_all(stdin)));
+
+        let stdout = scope.spawn(move || read_pipe(child_stdout));
+        let stderr = scope.spawn(move || read_pipe(child_stderr));
+        let status = wait(&mut child);
+
+        (
+            status,
+            stdout.join().unwrap_or_default(),
+            stderr.join().unwrap_or_default(),
+        )
+    });
+
+    if status?.success() {
+        Ok(String::from_utf8_lossy(&stdout).to_string())
+    } else {
+        Err(Error::HttpPostFailed(format!(
+            "{}: {}",
+            url,
+            String::from_utf8_lossy(&stderr).trim()
+        )))
+    }
+}
+
+fn read_pipe(pipe: Option<impl Read>) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    if let Some(mut pipe) = pipe {
+        pipe.read_to_end(&mut data).ok();
+    }
+
+    data
+}
+
+/// Waits for curl to exit, killing it once the plugin has been stopping for [`CANCEL_GRACE`].
+fn wait(child: &mut Child) -> Result<ExitStatus> {
+    let mut cancelled_at = None;
+
+    loop {
+        if let Some(status) = child.try_wait().map_err(Error::Io)? {
+            return Ok(status);
+        }
+
+        if worker::is_cancelled()
+            && cancelled_at.get_or_insert_with(Instant::now).elapsed() >= CANCEL_GRACE
+        {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(Error::Cancelled);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::deadbeef::testing;
+
+    #[test]
+    fn headers_and_url_go_through_a_removed_config_file() {
+        let _api = testing::setup();
+        let (url, requests) = testing::serve(Vec::new());
+        let header = r#"X-Token: a "quoted" \ value"#.to_string();
+
+        post(
+            &format!("{}/path?key=secret", url),
+            &[header],
+            Body::Json("{}"),
+        )
+        .unwrap();
+
+        let request = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        let leftovers = fs::read_dir(env::temp_dir())
+            .unwrap()
+            .flatten()
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(&format!("discordrpc-curl-{}-", process::id()))
+            })
+            .count();
+
+        assert!(request.head.starts_with("POST /path?key=secret HTTP/1.1"));
+        assert_eq!(request.header("X-Token"), Some(r#"a "quoted" \ value"#));
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        assert_eq!(request.body, "{}");
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn line_breaks_cannot_add_options() {
+        assert_eq!(
+            quote("value\"\nurl = \"https://example.com"),
+            r#""value\" url = \"https://example.com""#
+        );
+    }
+}
@@ -1,25 +1,67 @@
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
 use urlencoding::encode;
 
 use crate::{
     deadbeef::DB_functions_t,
     error::{Error, Result},
+    util::MutexExt,
+    worker,
 };
 
+lazy_static! {
+    /// Files of requests in flight, stored as addresses so shutdown can abort them.
+    static ref ACTIVE_REQUESTS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+}
+
 impl DB_functions_t {
     pub fn http_get(&self, url: &str) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.http_get_bytes(url)?).to_string())
+    }
+
+    pub fn http_get_bytes(&self, url: &str) -> Result<Vec<u8>> {
+        if worker::is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+
         let client = self.fopen(&url)?;
         if client.is_null() {
             return Err(Error::HttpGetFailed(url.to_string()));
         }
-        let length = self.fgetlength(&client)?;
-        if length < 0 {
-            return Err(Error::HttpGetFailed(url.to_string()));
+
+        ACTIVE_REQUESTS
+            .lock_recover()
+            .push(client.as_ptr() as usize);
+
+        let result = (|| -> Result<Vec<u8>> {
+            let length = self.fgetlength(&client)?;
+            if length < 0 {
+                return Err(Error::HttpGetFailed(url.to_string()));
+            }
+            let mut buffer: Vec<u8> = vec![0; length as usize];
+
+            self.log_debug(format!("HTTP GET: {} ({} bytes)", url, length));
+            self.fread(buffer.as_mut_ptr() as *mut _, 1, length as usize, &client)?;
+
+            Ok(buffer)
+        })();
+
+        ACTIVE_REQUESTS
+            .lock_recover()
+            .retain(|file| *file != client.as_ptr() as usize);
+
+        if worker::is_cancelled() {
+            return Err(Error::Cancelled);
         }
-        let mut buffer: Vec<u8> = vec![0; length as usize];
 
-        self.trace(format!("HTTP GET: {} ({} bytes)", url, length));
-        self.fread(buffer.as_mut_ptr() as *mut _, 1, length as usize, &client)?;
+        result
+    }
 
-        Ok(String::from_utf8_lossy(&buffer).to_string())
+    /// Aborts every request currently blocked in `http_get`.
+    pub fn abort_http_requests(&self) {
+        for file in ACTIVE_REQUESTS.lock_recover().iter() {
+            self.fabort(*file as *mut _).ok();
+        }
     }
 }
@@ -0,0 +1,118 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicI32, Ordering},
+    },
+    time::SystemTime,
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    config::{LogLevel, PLUGIN},
+    deadbeef::{
+        DB_functions_t, DB_plugin_s, DDB_LOG_LAYER_DEFAULT, DDB_LOG_LAYER_INFO, to_cstring,
+    },
+    util::{MutexExt, plugin_config_dir},
+};
+
+/// Log files are rotated to `discordrpc.log.1` once they grow past this size.
+const MAX_LOG_FILE_SIZE: u64 = 1024 * 1024;
+
+static LOG_LEVEL: AtomicI32 = AtomicI32::new(LogLevel::Warn as i32);
+static LOG_TO_FILE: AtomicBool = AtomicBool::new(false);
+lazy_static! {
+    static ref LOG_FILE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+impl LogLevel {
+    /// Errors and warnings are always shown; DeaDBeeF only shows the info layer once the
+    /// plugin's logging is switched on in its log window.
+    fn layer(self) -> u32 {
+        match self {
+            LogLevel::Error | LogLevel::Warn => DDB_LOG_LAYER_DEFAULT,
+            LogLevel::Info | LogLevel::Debug => DDB_LOG_LAYER_INFO,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+}
+
+impl DB_functions_t {
+    /// Applies the configured verbosity and log file setting.
+    pub fn set_log_config(&self, level: LogLevel, to_file: bool) {
+        LOG_LEVEL.store(level as i32, Ordering::SeqCst);
+        LOG_TO_FILE.store(to_file, Ordering::SeqCst);
+    }
+
+    pub fn log_error(&self, msg: String) {
+        self.log_message(LogLevel::Error, msg);
+    }
+
+    pub fn log_warn(&self, msg: String) {
+        self.log_message(LogLevel::Warn, msg);
+    }
+
+    pub fn log_info(&self, msg: String) {
+        self.log_message(LogLevel::Info, msg);
+    }
+
+    pub fn log_debug(&self, msg: String) {
+        self.log_message(LogLevel::Debug, msg);
+    }
+
+    /// Logs `msg` on the always visible layer, whatever the configured level.
+    pub fn log_always(&self, msg: String) {
+        self.write_log(LogLevel::Info, DDB_LOG_LAYER_DEFAULT, msg);
+    }
+
+    fn log_message(&self, level: LogLevel, msg: String) {
+        if level as i32 > LOG_LEVEL.load(Ordering::SeqCst) {
+            return;
+        }
+
+        self.write_log(level, level.layer(), msg);
+    }
+
+    fn write_log(&self, level: LogLevel, layer: u32, msg: String) {
+        let line = format!("[discordrpc] [{}] {}\n", level.label(), msg);
+        let c_line = to_cstring(&line);
+        let plugin = &PLUGIN.0.plugin as *const DB_plugin_s as *mut DB_plugin_s;
+
+        self.log_detailed(plugin, layer, &c_line).ok();
+
+        if LOG_TO_FILE.load(Ordering::SeqCst) {
+            write_log_file(&line);
+        }
+    }
+}
+
+fn write_log_file(line: &str) {
+    let _lock = LOG_FILE_LOCK.lock_recover();
+    let Ok(dir) = plugin_config_dir() else {
+        return;
+    };
+    let path = dir.join("discordrpc.log");
+
+    if fs::metadata(&path).is_ok_and(|metadata| metadata.len() > MAX_LOG_FILE_SIZE) {
+        fs::rename(&path, dir.join("discordrpc.log.1")).ok();
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&path) {
+        let _ = write!(file, "{} {}", timestamp, line);
+    }
+}
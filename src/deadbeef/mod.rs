@@ -9,13 +9,15 @@
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
 mod http;
+mod log;
 pub mod safe_wrapper;
+#[cfg(test)]
+pub mod testing;
 
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::ffi::c_void;
 
-use crate::config::PLUGIN;
 use crate::deadbeef::safe_wrapper::SafeDBFile;
 use crate::deadbeef::safe_wrapper::SafeDBPlayItem;
 use crate::deadbeef::safe_wrapper::SafeDBPlayList;
@@ -38,6 +40,11 @@ macro_rules! call_optional_fn {
     };
 }
 
+/// Converts `s` for C, dropping interior NUL bytes (e.g. from tags) instead of failing.
+pub fn to_cstring(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap_or_default()
+}
+
 impl DB_functions_t {
     pub fn streamer_get_playing_track(&self) -> Result<SafeDBPlayItem> {
         let ptr = call_optional_fn!(self.streamer_get_playing_track)?;
@@ -51,6 +58,84 @@ impl DB_functions_t {
         Ok(SafeDBPlayList::new(ptr))
     }
 
+    pub fn pl_get_playlist(&self, item: &SafeDBPlayItem) -> Result<SafeDBPlayList> {
+        let ptr = call_optional_fn!(self.pl_get_playlist, item.as_ptr())?;
+
+        Ok(SafeDBPlayList::new(ptr))
+    }
+
+    pub fn plt_get_title(&self, plt: &SafeDBPlayList) -> Result<String> {
+        let mut buf = vec![0u8; 256];
+
+        call_optional_fn!(
+            self.plt_get_title,
+            plt.as_ptr(),
+            buf.as_mut_ptr() as *mut i8,
+            buf.len() as i32
+        )?;
+        let c_str = CStr::from_bytes_until_nul(&buf).map_err(Error::FromBytesUntilNulError)?;
+
+        Ok(c_str.to_string_lossy().to_string())
+    }
+
+    pub fn action_get_playlist(&self) -> Result<SafeDBPlayList> {
+        let ptr = call_optional_fn!(self.action_get_playlist)?;
+
+        Ok(SafeDBPlayList::new(ptr))
+    }
+
+    pub fn plt_get_first(&self, plt: &SafeDBPlayList, iter: u32) -> Result<SafeDBPlayItem> {
+        let ptr = call_optional_fn!(self.plt_get_first, plt.as_ptr(), iter as i32)?;
+
+        Ok(SafeDBPlayItem::new(ptr))
+    }
+
+    pub fn pl_get_next(&self, item: &SafeDBPlayItem, iter: u32) -> Result<SafeDBPlayItem> {
+        let ptr = call_optional_fn!(self.pl_get_next, item.as_ptr(), iter as i32)?;
+
+        Ok(SafeDBPlayItem::new(ptr))
+    }
+
+    pub fn plt_get_item_idx(
+        &self,
+        plt: &SafeDBPlayList,
+        item: &SafeDBPlayItem,
+        iter: u32,
+    ) -> Result<i32> {
+        call_optional_fn!(
+            self.plt_get_item_idx,
+            plt.as_ptr(),
+            item.as_ptr(),
+            iter as i32
+        )
+    }
+
+    pub fn plt_get_item_count(&self, plt: &SafeDBPlayList, iter: u32) -> Result<i32> {
+        call_optional_fn!(self.plt_get_item_count, plt.as_ptr(), iter as i32)
+    }
+
+    pub fn playqueue_get_count(&self) -> Result<i32> {
+        call_optional_fn!(self.playqueue_get_count)
+    }
+
+    pub fn playqueue_get_item(&self, n: i32) -> Result<SafeDBPlayItem> {
+        let ptr = call_optional_fn!(self.playqueue_get_item, n)?;
+
+        Ok(SafeDBPlayItem::new(ptr))
+    }
+
+    pub fn streamer_get_shuffle(&self) -> Result<ddb_shuffle_t> {
+        call_optional_fn!(self.streamer_get_shuffle)
+    }
+
+    pub fn pl_is_selected(&self, item: &SafeDBPlayItem) -> Result<bool> {
+        Ok(call_optional_fn!(self.pl_is_selected, item.as_ptr())? != 0)
+    }
+
+    pub fn pl_item_ref(&self, item: *mut DB_playItem_s) -> Result<()> {
+        call_optional_fn!(self.pl_item_ref, item)
+    }
+
     pub fn pl_item_unref(&self, item: *mut DB_playItem_s) -> Result<()> {
         call_optional_fn!(self.pl_item_unref, item)
     }
@@ -63,6 +148,18 @@ impl DB_functions_t {
         call_optional_fn!(self.pl_get_item_duration, item.as_ptr())
     }
 
+    pub fn pl_item_get_startsample(&self, item: &SafeDBPlayItem) -> Result<i64> {
+        call_optional_fn!(self.pl_item_get_startsample, item.as_ptr())
+    }
+
+    pub fn pl_item_get_endsample(&self, item: &SafeDBPlayItem) -> Result<i64> {
+        call_optional_fn!(self.pl_item_get_endsample, item.as_ptr())
+    }
+
+    pub fn pl_find_meta_int(&self, item: &SafeDBPlayItem, key: *const i8, def: i32) -> Result<i32> {
+        call_optional_fn!(self.pl_find_meta_int, item.as_ptr(), key, def)
+    }
+
     pub fn pl_lock(&self) -> Result<()> {
         call_optional_fn!(self.pl_lock)
     }
@@ -89,6 +186,14 @@ impl DB_functions_t {
         call_optional_fn!(self.playback_get_pos)
     }
 
+    pub fn streamer_get_playpos(&self) -> Result<f32> {
+        call_optional_fn!(self.streamer_get_playpos)
+    }
+
+    pub fn plug_get_for_id(&self, id: &CStr) -> Result<*mut DB_plugin_t> {
+        call_optional_fn!(self.plug_get_for_id, id.as_ptr())
+    }
+
     pub fn thread_start(
         &self,
         func: unsafe extern "C" fn(*mut c_void),
@@ -100,7 +205,8 @@ impl DB_functions_t {
 
 impl DB_functions_t {
     pub fn tf_compile(&self, script: &str) -> Result<SafeDBTitleFormat> {
-        let ptr = call_optional_fn!(self.tf_compile, CString::new(script).unwrap().as_ptr())?;
+        let c_script = to_cstring(script);
+        let ptr = call_optional_fn!(self.tf_compile, c_script.as_ptr())?;
 
         Ok(SafeDBTitleFormat::new(ptr))
     }
@@ -141,11 +247,21 @@ impl DB_functions_t {
     pub fn conf_get_int(&self, key: *const i8, def: i32) -> Result<i32> {
         call_optional_fn!(self.conf_get_int, key, def)
     }
+
+    pub fn get_system_dir(&self, dir_id: u32) -> Result<String> {
+        let ptr = call_optional_fn!(self.get_system_dir, dir_id as i32)?;
+
+        if ptr.is_null() {
+            return Err(Error::MissingFunction);
+        }
+
+        Ok(unsafe { CStr::from_ptr(ptr) }.to_string_lossy().to_string())
+    }
 }
 
 impl DB_functions_t {
     pub fn fopen(&self, url: &str) -> Result<SafeDBFile> {
-        let c_str = CString::new(url).unwrap();
+        let c_str = to_cstring(url);
         let ptr = call_optional_fn!(self.fopen, c_str.as_ptr())?;
 
         Ok(SafeDBFile::new(ptr))
@@ -155,6 +271,10 @@ impl DB_functions_t {
         call_optional_fn!(self.fclose, file)
     }
 
+    pub fn fabort(&self, file: *mut DB_FILE) -> Result<()> {
+        call_optional_fn!(self.fabort, file)
+    }
+
     pub fn fgetlength(&self, file: &SafeDBFile) -> Result<i64> {
         call_optional_fn!(self.fgetlength, file.as_ptr())
     }
@@ -171,34 +291,15 @@ impl DB_functions_t {
 }
 
 impl DB_functions_t {
-    fn log_detailed(
-        &self,
-        plugin: *mut DB_plugin_s,
-        layers: u32,
-        char: *const i8,
-        args: va_list,
-    ) -> Result<()> {
-        call_optional_fn!(self.log_detailed, plugin, layers, char, args)
-    }
-
-    pub fn trace(&self, msg: String) {
-        let c_msg = CString::new(msg).unwrap();
-        let plugin = &PLUGIN.0.plugin as *const DB_plugin_s as *mut DB_plugin_s;
-
-        if cfg!(debug_assertions) {
-            let plugin_id = unsafe { CStr::from_ptr(PLUGIN.0.plugin.id) }.to_string_lossy();
-            let msg_str = c_msg.to_string_lossy();
-
-            println!("[Deadbeef][{}] {}", plugin_id, msg_str);
-        } else {
-            // TODO: idk where this actually shows up.
-            // log_detailed only appears in Deadbeef debug builds or development versions.
-            // In a normal release build, this message will not be visible.
-            self.log_detailed(plugin, DDB_LOG_LAYER_DEFAULT, c_msg.as_ptr(), unsafe {
-                std::mem::zeroed()
-            })
-            .ok();
-        };
+    fn log_detailed(&self, plugin: *mut DB_plugin_s, layers: u32, msg: &CStr) -> Result<()> {
+        // Pass the message as an argument so `%` in titles isn't read as a directive.
+        call_optional_fn!(
+            self.log_detailed,
+            plugin,
+            layers,
+            c"%s".as_ptr(),
+            msg.as_ptr()
+        )
     }
 }
 
@@ -23,9 +23,9 @@ macro_rules! safe_wrapper {
 
         impl Drop for $name {
             fn drop(&mut self) {
-                let api = API.get().unwrap();
-
-                if !self.inner.is_null() {
+                if let Some(api) = API.get()
+                    && !self.inner.is_null()
+                {
                     let _ = api.$free(self.inner);
                 }
             }
@@ -0,0 +1,421 @@
+//! A stand-in for DeaDBeeF's function table, so tests can run code that reads the
+//! configuration, formats the playing track, starts workers or writes to the plugin's
+//! directory, plus a local HTTP server for the sinks that post somewhere.
+
+use std::{
+    collections::HashMap,
+    env,
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    io::{Read, Write},
+    mem,
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    process,
+    ptr::{self, NonNull},
+    sync::{
+        LazyLock, Mutex, MutexGuard, Once,
+        mpsc::{self, Receiver},
+    },
+    thread,
+};
+
+use crate::{
+    API,
+    config::{ActivityKind, StatusDisplay},
+    deadbeef::{
+        DB_functions_t, DB_output_t, DB_playItem_t, ddb_playback_state_e_DDB_PLAYBACK_STATE_PAUSED,
+        ddb_playback_state_e_DDB_PLAYBACK_STATE_PLAYING, ddb_playback_state_t, ddb_playlist_t,
+        ddb_tf_context_t,
+    },
+    discordrpc::Presence,
+    util::MutexExt,
+};
+
+static INSTALL: Once = Once::new();
+/// Held by tests that use the configuration or other plugin-wide state.
+static SERIAL: Mutex<()> = Mutex::new(());
+static CONFIG: LazyLock<Mutex<HashMap<String, String>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+static CONFIG_DIR: LazyLock<CString> = LazyLock::new(|| {
+    let dir = env::temp_dir().join(format!("discordrpc-test-{}", process::id()));
+
+    CString::new(dir.to_string_lossy().as_bytes()).unwrap()
+});
+static PLAYING: LazyLock<Mutex<Option<Playing>>> = LazyLock::new(|| Mutex::new(None));
+/// Address of the output plugin handed out by `get_output`.
+static OUTPUT: LazyLock<usize> = LazyLock::new(|| {
+    let mut output: DB_output_t = unsafe { mem::zeroed() };
+
+    output.state = Some(output_state);
+
+    Box::leak(Box::new(output)) as *mut DB_output_t as usize
+});
+
+/// A track for [`play`], as DeaDBeeF would report it.
+#[derive(Debug, Clone, Default)]
+pub struct Track {
+    /// Tags and properties such as `:URI` or `:SAMPLERATE`, by key.
+    pub meta: HashMap<String, String>,
+    pub duration: f32,
+    pub start_sample: i64,
+    pub end_sample: i64,
+    /// Seconds into the track.
+    pub position: f32,
+    pub paused: bool,
+}
+
+struct Playing {
+    track: Track,
+    /// `track.meta` as handed out by `pl_find_meta`.
+    meta: HashMap<String, CString>,
+}
+
+/// Installs the stand-in API with an empty configuration and nothing playing, and returns a
+/// guard that keeps other tests from changing them meanwhile.
+pub fn setup() -> MutexGuard<'static, ()> {
+    let guard = SERIAL.lock_recover();
+
+    INSTALL.call_once(|| {
+        let mut api: DB_functions_t = unsafe { mem::zeroed() };
+
+        api.conf_get_str = Some(conf_get_str);
+        api.conf_get_int = Some(conf_get_int);
+        api.get_system_dir = Some(get_system_dir);
+        api.thread_start = Some(thread_start);
+        api.streamer_get_playing_track = Some(streamer_get_playing_track);
+        api.streamer_get_playpos = Some(streamer_get_playpos);
+        api.plt_get_curr = Some(plt_get_curr);
+        api.pl_item_unref = Some(pl_item_unref);
+        api.plt_unref = Some(plt_unref);
+        api.get_output = Some(get_output);
+        api.pl_get_item_duration = Some(pl_get_item_duration);
+        api.pl_item_get_startsample = Some(pl_item_get_startsample);
+        api.pl_item_get_endsample = Some(pl_item_get_endsample);
+        api.pl_find_meta = Some(pl_find_meta);
+        api.pl_find_meta_int = Some(pl_find_meta_int);
+        api.pl_lock = Some(pl_lock);
+        api.pl_unlock = Some(pl_lock);
+        api.is_local_file = Some(is_local_file);
+        api.tf_compile = Some(tf_compile);
+        api.tf_free = Some(tf_free);
+        api.tf_eval = Some(tf_eval);
+
+        API.set(Box::leak(Box::new(api))).ok();
+    });
+    CONFIG.lock_recover().clear();
+    *PLAYING.lock_recover() = None;
+
+    guard
+}
+
+pub fn set_conf(key: *const i8, value: impl ToString) {
+    let key = unsafe { CStr::from_ptr(key) }.to_string_lossy().to_string();
+
+    CONFIG.lock_recover().insert(key, value.to_string());
+}
+
+/// Makes `track` the playing track.
+pub fn play(track: Track) {
+    let meta = track
+        .meta
+        .iter()
+        .map(|(key, value)| {
+            let value = value.split('\0').next().unwrap_or_default();
+
+            (key.clone(), CString::new(value).unwrap())
+        })
+        .collect();
+
+    *PLAYING.lock_recover() = Some(Playing { track, meta });
+}
+
+fn conf_value(key: *const c_char) -> Option<String> {
+    let key = unsafe { CStr::from_ptr(key) }.to_string_lossy();
+
+    CONFIG.lock_recover().get(key.as_ref()).cloned()
+}
+
+unsafe extern "C" fn conf_get_str(
+    key: *const c_char,
+    def: *const c_char,
+    buffer: *mut c_char,
+    buffer_size: c_int,
+) {
+    let value = conf_value(key)
+        .unwrap_or_else(|| unsafe { CStr::from_ptr(def) }.to_string_lossy().to_string());
+
+    unsafe { write_out(value.as_bytes(), buffer, buffer_size) };
+}
+
+unsafe extern "C" fn conf_get_int(key: *const c_char, def: c_int) -> c_int {
+    conf_value(key)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(def)
+}
+
+unsafe extern "C" fn get_system_dir(_: c_int) -> *const c_char {
+    CONFIG_DIR.as_ptr()
+}
+
+unsafe extern "C" fn thread_start(
+    func: Option<unsafe extern "C" fn(*mut c_void)>,
+    ctx: *mut c_void,
+) -> isize {
+    let Some(func) = func else {
+        return 0;
+    };
+    let ctx = ctx as usize;
+
+    thread::spawn(move || unsafe { func(ctx as *mut c_void) });
+
+    1
+}
+
+/// Copies as much of `value` into `buffer` as fits with the terminating NUL, cutting through
+/// multi-byte characters like DeaDBeeF does. Returns the number of bytes copied.
+unsafe fn write_out(value: &[u8], buffer: *mut c_char, buffer_size: c_int) -> c_int {
+    let len = value.len().min(buffer_size.max(1) as usize - 1);
+
+    unsafe {
+        ptr::copy_nonoverlapping(value.as_ptr(), buffer as *mut u8, len);
+        *buffer.add(len) = 0;
+    }
+
+    len as c_int
+}
+
+fn with_track<T>(default: T, f: impl FnOnce(&Playing) -> T) -> T {
+    PLAYING.lock_recover().as_ref().map_or(default, f)
+}
+
+unsafe extern "C" fn streamer_get_playing_track() -> *mut DB_playItem_t {
+    with_track(ptr::null_mut(), |_| NonNull::dangling().as_ptr())
+}
+
+unsafe extern "C" fn streamer_get_playpos() -> f32 {
+    with_track(0.0, |playing| playing.track.position)
+}
+
+unsafe extern "C" fn plt_get_curr() -> *mut ddb_playlist_t {
+    NonNull::dangling().as_ptr()
+}
+
+unsafe extern "C" fn pl_item_unref(_: *mut DB_playItem_t) {}
+
+unsafe extern "C" fn plt_unref(_: *mut ddb_playlist_t) {}
+
+unsafe extern "C" fn get_output() -> *mut DB_output_t {
+    *OUTPUT as *mut DB_output_t
+}
+
+unsafe extern "C" fn output_state() -> ddb_playback_state_t {
+    with_track(0, |playing| {
+        if playing.track.paused {
+            ddb_playback_state_e_DDB_PLAYBACK_STATE_PAUSED
+        } else {
+            ddb_playback_state_e_DDB_PLAYBACK_STATE_PLAYING
+        }
+    })
+}
+
+unsafe extern "C" fn pl_get_item_duration(_: *mut DB_playItem_t) -> f32 {
+    with_track(-1.0, |playing| playing.track.duration)
+}
+
+unsafe extern "C" fn pl_item_get_startsample(_: *mut DB_playItem_t) -> i64 {
+    with_track(0, |playing| playing.track.start_sample)
+}
+
+unsafe extern "C" fn pl_item_get_endsample(_: *mut DB_playItem_t) -> i64 {
+    with_track(0, |playing| playing.track.end_sample)
+}
+
+unsafe extern "C" fn pl_find_meta(_: *mut DB_playItem_t, key: *const c_char) -> *const c_char {
+    let key = unsafe { CStr::from_ptr(key) }.to_string_lossy();
+
+    // Points into PLAYING, which only changes between test steps.
+    with_track(ptr::null(), |playing| {
+        playing
+            .meta
+            .get(key.as_ref())
+            .map_or(ptr::null(), |value| value.as_ptr())
+    })
+}
+
+unsafe extern "C" fn pl_find_meta_int(
+    item: *mut DB_playItem_t,
+    key: *const c_char,
+    def: c_int,
+) -> c_int {
+    let value = unsafe { pl_find_meta(item, key) };
+
+    if value.is_null() {
+        return def;
+    }
+
+    unsafe { CStr::from_ptr(value) }
+        .to_string_lossy()
+        .trim()
+        .parse()
+        .unwrap_or(def)
+}
+
+unsafe extern "C" fn pl_lock() {}
+
+unsafe extern "C" fn is_local_file(fname: *const c_char) -> c_int {
+    let local = fname.is_null() || !unsafe { CStr::from_ptr(fname) }.to_bytes().contains(&b':');
+
+    local as c_int
+}
+
+unsafe extern "C" fn tf_compile(script: *const c_char) -> *mut c_char {
+    unsafe { CStr::from_ptr(script) }.to_owned().into_raw()
+}
+
+unsafe extern "C" fn tf_free(code: *mut c_char) {
+    drop(unsafe { CString::from_raw(code) });
+}
+
+/// Replaces `%field%` with the playing track's tag, leaving everything else as written.
+unsafe extern "C" fn tf_eval(
+    _: *mut ddb_tf_context_t,
+    code: *const c_char,
+    out: *mut c_char,
+    outlen: c_int,
+) -> c_int {
+    let script = unsafe { CStr::from_ptr(code) }.to_bytes();
+    let mut value = Vec::new();
+    let mut rest = script;
+
+    while let Some(start) = rest.iter().position(|byte| *byte == b'%') {
+        value.extend_from_slice(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        match rest.iter().position(|byte| *byte == b'%') {
+            Some(end) => {
+                let field = String::from_utf8_lossy(&rest[..end]).to_string();
+
+                with_track((), |playing| {
+                    if let Some(meta) = playing.meta.get(&field) {
+                        value.extend_from_slice(meta.to_bytes());
+                    }
+                });
+                rest = &rest[end + 1..];
+            }
+            None => value.push(b'%'),
+        }
+    }
+    value.extend_from_slice(rest);
+
+    unsafe { write_out(&value, out, outlen) }
+}
+
+/// A presence for `track` with every field filled in.
+pub fn presence(track: usize) -> Presence {
+    Presence {
+        details: "Song".to_string(),
+        state: "Artist".to_string(),
+        large_text: "Album".to_string(),
+        large_image: "https://example.com/cover.jpg".to_string(),
+        buttons: vec![("Listen".to_string(), "https://example.com/".to_string())],
+        party: Some(("album".to_string(), [3, 12])),
+        start_timestamp: Some(1000),
+        end_timestamp: Some(1200),
+        activity_kind: ActivityKind::Listening,
+        status_display: StatusDisplay::Details,
+        track,
+        play: 1,
+        cover_key: None,
+        export: None,
+        track_fields: None,
+    }
+}
+
+/// A request received by [`serve`].
+#[derive(Debug)]
+pub struct Request {
+    /// Request line and headers.
+    pub head: String,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+}
+
+/// Serves HTTP on a free local port, answering the requests with `statuses` in turn and 200
+/// once they run out. Returns the base URL and the requests as they arrive.
+pub fn serve(statuses: Vec<u16>) -> (String, Receiver<Request>) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut statuses = statuses.into_iter();
+
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                return;
+            };
+            let request = read_request(&mut stream);
+            let status = statuses.next().unwrap_or(200);
+
+            write!(
+                stream,
+                "HTTP/1.1 {} Test\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{{}}",
+                status
+            )
+            .ok();
+
+            if sender.send(request).is_err() {
+                return;
+            }
+        }
+    });
+
+    (url, receiver)
+}
+
+fn read_request(stream: &mut TcpStream) -> Request {
+    let mut data = Vec::new();
+    let mut buffer = [0; 4096];
+    let head_end = loop {
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+
+        let len = stream.read(&mut buffer).unwrap_or(0);
+
+        if len == 0 {
+            break data.len();
+        }
+        data.extend_from_slice(&buffer[..len]);
+    };
+    let head = String::from_utf8_lossy(&data[..head_end]).to_string();
+    let mut request = Request {
+        head,
+        body: String::new(),
+    };
+    let length = request
+        .header("Content-Length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = data.split_off((head_end + 4).min(data.len()));
+
+    while body.len() < length {
+        let len = stream.read(&mut buffer).unwrap_or(0);
+
+        if len == 0 {
+            break;
+        }
+        body.extend_from_slice(&buffer[..len]);
+    }
+    request.body = String::from_utf8_lossy(&body).to_string();
+
+    request
+}
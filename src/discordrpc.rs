@@ -1,19 +1,56 @@
-use std::time::SystemTime;
+use std::{
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
 
-use discord_rich_presence::{
-    DiscordIpc, DiscordIpcClient,
-    activity::{Activity, ActivityType, Assets, Timestamps},
+use discord_rich_presence::activity::{
+    Activity, ActivityType, Assets, Button, Party, StatusDisplayType, Timestamps,
 };
+use lazy_static::lazy_static;
 
 use crate::{
     API, DRPC,
-    config::{ConfigDefault, ConfigKey, CoverSource},
-    deadbeef::ddb_playback_state_e_DDB_PLAYBACK_STATE_PLAYING,
+    cleanup::{Cleanup, CleanupTarget},
+    config::{ActivityKind, ConfigDefault, ConfigKey, PartyMode, StatusDisplay},
+    cover::{CoverRequest, next_item, validate_large_image},
+    deadbeef::{
+        PL_MAIN, ddb_playback_state_e_DDB_PLAYBACK_STATE_PLAYING,
+        safe_wrapper::{SafeDBPlayItem, SafeDBPlayList},
+    },
     error::{Error, Result},
-    musicbrainz::get_album_cover_url_from_query,
-    util::{is_streaming, nowplaying_format_string, nowplaying_length},
+    export::ExportTemplates,
+    ipc::{ConnectionSettings, DiscordConnection},
+    lyrics::{LyricLine, load_lyrics, lyrics_at, start_lyrics, stop_lyrics},
+    overrides::{nowplaying_cover_override, nowplaying_tag_overrides},
+    profile::{Profile, select_profile},
+    romanize::Romanizer,
+    rotation::{start_rotation, stop_rotation},
+    sink::{self, PresenceSink, SinkEvent, render_track_fields},
+    status,
+    util::{
+        MutexExt, content_hash, format_string, is_streaming, nowplaying_format_string,
+        nowplaying_length, nowplaying_position,
+    },
+    worker,
 };
 
+/// Discord rejects details, state and image texts longer than this many characters.
+const MAX_FIELD_LEN: usize = 128;
+/// Discord accepts five activity updates per 20 seconds.
+pub const MIN_UPDATE_INTERVAL: Duration = Duration::from_secs(4);
+const PARTY_ID_SCRIPT: &str = "%album artist%|%album%";
+
+/// Bumped on every song change, so a track repeated on its own counts as a new play.
+static PLAY_COUNT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref LAST_PLAYED: Mutex<Option<String>> = Mutex::new(None);
+    static ref CURRENT_PRESENCE: Mutex<Option<Presence>> = Mutex::new(None);
+}
+
 #[repr(u32)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Status {
@@ -23,38 +60,245 @@ pub enum Status {
     Start = 4,
 }
 
-pub fn clear_activity() -> Result<()> {
-    let api = API.get().unwrap();
-    let mut drpc = DRPC.lock().unwrap();
+/// The track presence currently shown, as published to every sink.
+#[derive(Debug, Clone)]
+pub struct Presence {
+    pub details: String,
+    pub state: String,
+    pub large_text: String,
+    pub large_image: String,
+    /// Labels and URLs of the buttons under the presence.
+    pub buttons: Vec<(String, String)>,
+    /// Party id and the current/total counter shown as "(3 of 12)".
+    pub party: Option<(String, [i32; 2])>,
+    pub start_timestamp: Option<i64>,
+    pub end_timestamp: Option<i64>,
+    pub activity_kind: ActivityKind,
+    pub status_display: StatusDisplay,
+    /// Playing item the presence was built for.
+    pub track: usize,
+    /// Play of `track` the presence belongs to; differs when the same item plays again.
+    pub play: u64,
+    /// Cache key of a cover still being looked up, if any.
+    pub cover_key: Option<String>,
+    /// Overlay export templates, `None` while the export is disabled.
+    pub export: Option<ExportTemplates>,
+    /// Raw track fields for the now playing server, `None` while it is disabled.
+    pub track_fields: Option<Vec<(&'static str, String)>>,
+}
+
+impl Presence {
+    pub fn activity(&self) -> Activity<'_> {
+        let mut timestamps = Timestamps::new();
+
+        if let Some(start) = self.start_timestamp {
+            timestamps = timestamps.start(start);
+        }
+        if let Some(end) = self.end_timestamp {
+            timestamps = timestamps.end(end);
+        }
+
+        let mut assets = Assets::new().large_image(&self.large_image);
+
+        if !self.large_text.is_empty() {
+            assets = assets.large_text(&self.large_text);
+        }
+
+        let mut activity = Activity::new()
+            .details(&self.details)
+            .timestamps(timestamps)
+            .assets(assets)
+            .activity_type(self.activity_kind.into())
+            .status_display_type(self.status_display.into());
+
+        if !self.state.is_empty() {
+            activity = activity.state(&self.state);
+        }
+
+        let activity = match &self.party {
+            Some((id, size)) => activity.party(Party::new().id(id).size(*size)),
+            None => activity,
+        };
+
+        if self.buttons.is_empty() {
+            activity
+        } else {
+            activity.buttons(
+                self.buttons
+                    .iter()
+                    .map(|(label, url)| Button::new(label, url))
+                    .collect(),
+            )
+        }
+    }
+}
+
+impl From<ActivityKind> for ActivityType {
+    fn from(kind: ActivityKind) -> Self {
+        match kind {
+            ActivityKind::Listening => ActivityType::Listening,
+            ActivityKind::Playing => ActivityType::Playing,
+            ActivityKind::Watching => ActivityType::Watching,
+            ActivityKind::Competing => ActivityType::Competing,
+        }
+    }
+}
 
-    if let Some(drpc) = &mut *drpc {
-        api.trace("Clearing Discord activity.".to_string());
-        drpc.clear_activity().map_err(Error::DiscordFailed)?;
+impl From<StatusDisplay> for StatusDisplayType {
+    fn from(display: StatusDisplay) -> Self {
+        match display {
+            StatusDisplay::Name => StatusDisplayType::Name,
+            StatusDisplay::State => StatusDisplayType::State,
+            StatusDisplay::Details => StatusDisplayType::Details,
+        }
     }
+}
+
+/// Sends the presence to Discord over the connection in [`DRPC`].
+pub struct DiscordSink;
+
+impl DiscordSink {
+    fn set_activity(&self, activity: Activity) -> Result<()> {
+        let mut drpc = DRPC.lock_recover();
+
+        // Checked under the lock so nothing is sent after shutdown cleared the activity.
+        if worker::is_cancelled() {
+            return Err(Error::Cancelled);
+        }
 
-    Ok(())
+        let drpc = drpc.as_mut().ok_or(Error::MissingFunction)?;
+
+        // Discord may not have been running at connect time, or restarted since.
+        if drpc.set_activity(activity.clone()).is_err() {
+            let result = drpc.reconnect();
+
+            status::set_clients(drpc.clients());
+            result
+                .and_then(|()| drpc.set_activity(activity.clone()))
+                .map_err(Error::DiscordFailed)?;
+        }
+        status::set_last_activity(serde_json::to_string(&activity).ok());
+
+        Ok(())
+    }
+}
+
+impl PresenceSink for DiscordSink {
+    fn name(&self) -> &'static str {
+        "discord"
+    }
+
+    fn is_primary(&self) -> bool {
+        true
+    }
+
+    fn enabled(&self) -> Result<bool> {
+        let api = API.get().unwrap();
+
+        Ok(api.conf_get_int(ConfigKey::DRY_RUN, ConfigDefault::DRY_RUN)? == 0)
+    }
+
+    fn publish(&mut self, presence: &Presence) -> Result<()> {
+        self.set_activity(presence.activity())
+    }
+
+    fn idle(&mut self, presence: &Presence) -> Result<()> {
+        self.set_activity(presence.activity())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        // Nothing is shown while Discord isn't connected, and the next publish reconnects.
+        if let Some(drpc) = &mut *DRPC.lock_recover()
+            && !drpc.clients().is_empty()
+        {
+            drpc.clear_activity().map_err(Error::DiscordFailed)?;
+            status::set_last_activity(None);
+        }
+
+        Ok(())
+    }
+}
+
+pub fn clear_activity() -> Result<()> {
+    let mut current = CURRENT_PRESENCE.lock_recover();
+
+    stop_lyrics();
+    stop_rotation();
+    *current = None;
+    sink::publish_all(SinkEvent::Clear)
 }
 
 pub fn update_activity(playback_status: Status, nextitem_length: Option<f32>) -> Result<()> {
     let mut playback_status = playback_status;
     let api = API.get().unwrap();
+    let tag_overrides = nowplaying_tag_overrides()?;
 
-    let details_script = api.conf_get_str(ConfigKey::TITLE_SCRIPT, ConfigDefault::TITLE_SCRIPT)?;
-    let state_script = api.conf_get_str(ConfigKey::STATE_SCRIPT, ConfigDefault::STATE_SCRIPT)?;
-    let icon_text_script = api.conf_get_str(ConfigKey::ICON_SCRIPT, ConfigDefault::ICON_SCRIPT)?;
-    let timestamp_display_mode =
-        api.conf_get_int(ConfigKey::END_TIMESTAMP2, ConfigDefault::END_TIMESTAMP2)?;
-    let cover_source = CoverSource::try_from(
-        api.conf_get_int(ConfigKey::COVER_SOURCE, ConfigDefault::COVER_SOURCE)?,
-    )?;
+    let lyrics_generation = stop_lyrics();
+    let rotation_generation = stop_rotation();
+
+    // Checked before any cover lookup so hidden tracks never reach the network.
+    if tag_overrides.hide {
+        api.log_debug("Track is tagged DISCORD_HIDE, clearing activity.".to_string());
+        return clear_activity();
+    }
+
+    let nowplaying = api.streamer_get_playing_track()?;
+    let nowplaying_plt = api.plt_get_curr()?;
+    let profile = select_profile(&nowplaying, &nowplaying_plt)?;
+    let details_script = tag_overrides.details_script.unwrap_or(profile.title_script);
+    let state_script = tag_overrides.state_script.unwrap_or(profile.state_script);
+    let icon_text_script = tag_overrides
+        .icon_text_script
+        .unwrap_or(profile.icon_script);
 
     let hide_on_pause =
         api.conf_get_int(ConfigKey::HIDE_ON_PAUSE, ConfigDefault::HIDE_ON_PAUSE)? == 1;
 
-    let details = nowplaying_format_string(&details_script)?;
-    let state = nowplaying_format_string(&state_script)?;
-    let icon_text = nowplaying_format_string(&icon_text_script)?;
-    let mut timestamp = Timestamps::new();
+    let track = nowplaying.as_ptr() as usize;
+    let play = match playback_status {
+        Status::Songchanged => PLAY_COUNT.fetch_add(1, Ordering::SeqCst) + 1,
+        _ => PLAY_COUNT.load(Ordering::SeqCst),
+    };
+    let party = track_party(&nowplaying, &nowplaying_plt)?;
+    let lyrics = if api.conf_get_int(ConfigKey::LYRICS_ENABLE, ConfigDefault::LYRICS_ENABLE)? == 1 {
+        load_lyrics(&nowplaying, &nowplaying_plt)?
+    } else {
+        None
+    };
+    let cover_request = CoverRequest::new(nowplaying, &nowplaying_plt, profile.cover_source)?;
+    let cleanup = Cleanup::load(CleanupTarget::Presence)?;
+    let mut romanizer = Romanizer::load(Some(cover_request.album_query()))?;
+    let raw_details = cleanup.apply(&nowplaying_format_string(&details_script)?);
+    let raw_state = cleanup.apply(&nowplaying_format_string(&state_script)?);
+    let raw_icon_text = cleanup.apply(&nowplaying_format_string(&icon_text_script)?);
+    let details = truncate_field(romanizer.details(&raw_details));
+    let state = truncate_field(romanizer.state(&raw_state));
+    let icon_text = truncate_field(romanizer.icon_text(&raw_icon_text));
+    let rotating_states = rotating_states(&state, &cleanup, &romanizer)?;
+    let lyrics = lyrics.map(|lines| {
+        lines
+            .into_iter()
+            .map(|line| LyricLine {
+                text: romanizer.state(&line.text),
+                ..line
+            })
+            .collect::<Vec<_>>()
+    });
+    let export = ExportTemplates::render()?;
+    let track_fields = render_track_fields()?;
+    let mut buttons = Vec::new();
+
+    for (label_script, url_script) in &profile.buttons {
+        let label = nowplaying_format_string(label_script)?;
+        let url = nowplaying_format_string(url_script)?;
+
+        // Discord refuses the whole activity if a button has no label or a non-http URL.
+        if !label.is_empty() && (url.starts_with("https://") || url.starts_with("http://")) {
+            buttons.push((label, url));
+        }
+    }
+    let mut start_timestamp = None;
+    let mut end_timestamp = None;
 
     if let Status::Seeked = playback_status
         && let Some(output) = unsafe { api.get_output()?.as_ref() }
@@ -70,75 +314,595 @@ pub fn update_activity(playback_status: Status, nextitem_length: Option<f32>) ->
 
     match playback_status {
         Status::Songchanged | Status::Seeked | Status::Start => {
-            let mut start_timestamp = SystemTime::now()
+            let now = SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
                 .map_err(Error::SystemTimeError)?
                 .as_secs() as i64;
-            let mut end_timestamp: i64 = start_timestamp;
+            let (elapsed, length) = match (playback_status, nextitem_length) {
+                (Status::Songchanged, Some(length)) => (0.0, Some(length)),
+                (Status::Songchanged, None) => (0.0, nowplaying_length()?),
+                _ => (nowplaying_position()?, nowplaying_length()?),
+            };
+            let length = if profile.full_timestamps && !is_streaming()? {
+                length
+            } else {
+                None
+            };
+            let (start, end) = activity_timestamps(now, elapsed, length);
 
-            if playback_status != Status::Songchanged {
-                start_timestamp -= (nowplaying_length()? * api.playback_get_pos()? / 100.0) as i64;
-            }
+            start_timestamp = Some(start);
+            end_timestamp = end;
+        }
+        _ => {}
+    }
 
-            if timestamp_display_mode == 1 && !is_streaming()? {
-                if let Status::Songchanged = playback_status
-                    && let Some(length) = nextitem_length
-                {
-                    end_timestamp = start_timestamp + length as i64;
-                } else {
-                    end_timestamp = start_timestamp + nowplaying_length()? as i64;
-                }
+    let cover_override = tag_overrides.cover.or_else(|| {
+        nowplaying_cover_override().unwrap_or_else(|e| {
+            api.log_warn(format!("Failed to read cover overrides: {:?}", e));
+            None
+        })
+    });
+    // Without an override the text goes out right away with whatever cover is already
+    // known, and a cover that still needs a lookup is patched in once it resolves.
+    let (large_image, cover_request) = match cover_override {
+        Some(cover) => (cover, None),
+        None => match cover_request.cached() {
+            Some(cover) => (cover, None),
+            None => ("default".to_string(), Some(cover_request)),
+        },
+    };
+
+    *LAST_PLAYED.lock_recover() = Some(if state.is_empty() {
+        details.clone()
+    } else {
+        format!("{} - {}", details, state)
+    });
+
+    let lyrics_state = match &lyrics {
+        Some(lines) => {
+            let position = match playback_status {
+                Status::Songchanged => 0.0,
+                _ => nowplaying_position()?,
+            };
+
+            lyrics_at(lines, position).0.map(truncate_field)
+        }
+        None => None,
+    };
+
+    // A failed Discord update doesn't keep the other sinks from getting the cover, and is
+    // reported once they did.
+    let mut result = publish(Presence {
+        details,
+        state: lyrics_state.clone().unwrap_or_else(|| state.clone()),
+        large_text: icon_text,
+        large_image: validate_large_image(large_image),
+        buttons,
+        party,
+        start_timestamp,
+        end_timestamp,
+        activity_kind: profile.activity_kind,
+        status_display: profile.status_display,
+        track,
+        play,
+        cover_key: cover_request.as_ref().map(|request| request.key.clone()),
+        export,
+        track_fields,
+    });
+
+    if let Err(Error::Cancelled) = result {
+        return result;
+    }
+
+    // Started before the cover lookup, which can take a while. Lyrics take the place of the
+    // rotating states.
+    if playback_status != Status::Paused {
+        if let Some(lines) = lyrics {
+            start_lyrics(lyrics_generation, track, lines, state.clone(), lyrics_state)?;
+        } else if rotating_states.len() > 1 {
+            start_rotation(rotation_generation, track, rotating_states)?;
+        }
+    }
+
+    if let Some(request) = cover_request
+        && let Some(cover) = request.resolve()
+    {
+        result = result.and(patch_cover(
+            &request.key,
+            track,
+            validate_large_image(cover),
+        ));
+    }
+
+    // Latin names only become known with the release, which the cover lookup above may
+    // just have resolved.
+    if romanizer.awaits_latin_names(&raw_details, &raw_state, &raw_icon_text) {
+        match romanizer.resolve_latin_names() {
+            Ok(()) => {
+                result = result.and(patch_romanized(
+                    track,
+                    &state,
+                    truncate_field(romanizer.details(&raw_details)),
+                    truncate_field(romanizer.state(&raw_state)),
+                    truncate_field(romanizer.icon_text(&raw_icon_text)),
+                ))
             }
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(e) => api.log_debug(format!("Failed to resolve Latin names: {:?}", e)),
+        }
+    }
+
+    result
+}
+
+/// The state followed by the extra rotating state formats, skipping empty and repeated texts.
+///
+/// Formats starting with `next:` are evaluated against the track that plays next, and skipped
+/// when it isn't known.
+fn rotating_states(state: &str, cleanup: &Cleanup, romanizer: &Romanizer) -> Result<Vec<String>> {
+    let api = API.get().unwrap();
+    let scripts = api.conf_get_str(ConfigKey::STATE_ROTATION, ConfigDefault::STATE_ROTATION)?;
+    let mut states = vec![state.to_string()];
 
-            timestamp = Timestamps::new().start(start_timestamp).end(end_timestamp);
+    for script in scripts
+        .split('|')
+        .filter(|script| !script.trim().is_empty())
+    {
+        let text = match script.strip_prefix("next:") {
+            Some(script) => match next_item()? {
+                Some(item) => format_string(&item, &api.plt_get_curr()?, script)?,
+                None => continue,
+            },
+            None => nowplaying_format_string(script)?,
+        };
+        let text = truncate_field(romanizer.state(&cleanup.apply(&text)));
+
+        if !text.trim().is_empty() && !states.contains(&text) {
+            states.push(text);
         }
-        _ => {}
     }
 
-    let large_image = match cover_source {
-        CoverSource::MusicBrainz => {
-            let album_query_script = api.conf_get_str(
-                ConfigKey::QUERY_ALBUM_SCRIPT,
-                ConfigDefault::QUERY_ALBUM_SCRIPT,
-            )?;
-            let album_query = nowplaying_format_string(&album_query_script)?;
+    Ok(states)
+}
+
+/// Party for showing the track's position in its album or playlist, if enabled and known.
+fn track_party(item: &SafeDBPlayItem, plt: &SafeDBPlayList) -> Result<Option<(String, [i32; 2])>> {
+    let api = API.get().unwrap();
+    let mode =
+        PartyMode::try_from(api.conf_get_int(ConfigKey::PARTY_MODE, ConfigDefault::PARTY_MODE)?)?;
 
-            get_album_cover_url_from_query(&album_query).unwrap_or("default".to_string())
+    if mode == PartyMode::Off || item.is_null() {
+        return Ok(None);
+    }
+
+    // Tied to the album rather than the track so Discord keeps one counter across it.
+    let id = content_hash(format_string(item, plt, PARTY_ID_SCRIPT)?.as_bytes());
+    let size = match mode {
+        PartyMode::Off => None,
+        PartyMode::Album => {
+            let number = format_string(item, plt, "%tracknumber%")?;
+            // Some taggers write "3/12" into the track number.
+            let (number, total) = match number.split_once('/') {
+                Some((number, total)) => (number.to_string(), total.to_string()),
+                None => (number, format_string(item, plt, "%totaltracks%")?),
+            };
+
+            number
+                .trim()
+                .parse::<i32>()
+                .ok()
+                .zip(total.trim().parse::<i32>().ok())
+        }
+        PartyMode::Playlist => {
+            let item_plt = api.pl_get_playlist(item)?;
+            let plt = if item_plt.is_null() { plt } else { &item_plt };
+            let index = api.plt_get_item_idx(plt, item, PL_MAIN)?;
+            let count = api.plt_get_item_count(plt, PL_MAIN)?;
+
+            (index >= 0).then_some((index + 1, count))
         }
-        CoverSource::NoCover => "default".to_string(),
     };
 
-    api.trace(format!(
-        "Updating activity: details='{}', state='{}', large_image='{}', icon_text='{}'",
-        details, state, large_image, icon_text
-    ));
-
-    if let Some(drpc) = &mut *DRPC.lock().unwrap() {
-        drpc.set_activity(
-            Activity::new()
-                .details(&details)
-                .timestamps(timestamp)
-                .assets(
-                    Assets::new()
-                        .large_text(&icon_text)
-                        .large_image(&large_image),
-                )
-                .state(&state)
-                .activity_type(ActivityType::Listening),
-        )
-        .map_err(Error::DiscordFailed)?;
-    } else {
-        return Err(Error::MissingFunction);
+    Ok(size
+        .filter(|(current, total)| *current > 0 && current <= total)
+        .map(|(current, total)| (id, [current, total])))
+}
+
+/// Shortens `text` to the length Discord accepts for a text field.
+fn truncate_field(text: String) -> String {
+    if text.chars().count() <= MAX_FIELD_LEN {
+        return text;
+    }
+
+    let mut truncated = text.chars().take(MAX_FIELD_LEN - 1).collect::<String>();
+
+    truncated.push('…');
+    truncated
+}
+
+/// Publishes `presence` and remembers it as the one currently shown.
+fn publish(presence: Presence) -> Result<()> {
+    let mut current = CURRENT_PRESENCE.lock_recover();
+
+    publish_locked(&mut current, presence)
+}
+
+fn publish_locked(current: &mut Option<Presence>, presence: Presence) -> Result<()> {
+    if worker::is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+
+    // Every sink is updated even while Discord isn't running, and the presence is
+    // remembered either way so the resolved cover still reaches them.
+    let result = sink::publish_all(SinkEvent::Publish(&presence));
+
+    *current = Some(presence);
+    result
+}
+
+/// Swaps the resolved cover into the shown presence, unless the track or its cover changed
+/// in the meantime.
+fn patch_cover(cover_key: &str, track: usize, large_image: String) -> Result<()> {
+    let api = API.get().unwrap();
+    let mut current = CURRENT_PRESENCE.lock_recover();
+    let presence = match current.as_ref() {
+        Some(presence)
+            if presence.track == track && presence.cover_key.as_deref() == Some(cover_key) =>
+        {
+            Presence {
+                large_image,
+                cover_key: None,
+                ..presence.clone()
+            }
+        }
+        _ => {
+            api.log_debug("Presence changed before its cover resolved, dropping it.".to_string());
+            return Ok(());
+        }
+    };
+
+    publish_locked(&mut current, presence)
+}
+
+/// Swaps re-romanized texts into the shown presence, unless the track changed in the meantime.
+/// The state is only replaced while it still shows `shown_state`, not a lyric or rotating state.
+fn patch_romanized(
+    track: usize,
+    shown_state: &str,
+    details: String,
+    state: String,
+    large_text: String,
+) -> Result<()> {
+    let mut current = CURRENT_PRESENCE.lock_recover();
+    let presence = match current.as_ref() {
+        Some(presence) if presence.track == track => {
+            let state = if presence.state == shown_state {
+                state
+            } else {
+                presence.state.clone()
+            };
+
+            if presence.details == details
+                && presence.state == state
+                && presence.large_text == large_text
+            {
+                return Ok(());
+            }
+
+            Presence {
+                details,
+                state,
+                large_text,
+                ..presence.clone()
+            }
+        }
+        _ => return Ok(()),
+    };
+
+    publish_locked(&mut current, presence)
+}
+
+/// Replaces the state of the shown presence with a lyric line or rotating state, unless the
+/// track changed in the meantime. `is_current` is checked under the presence lock, so a worker
+/// that was just stopped can't overwrite the presence that replaced it.
+pub fn patch_state(track: usize, state: String, is_current: impl Fn() -> bool) -> Result<()> {
+    let mut current = CURRENT_PRESENCE.lock_recover();
+    let presence = match current.as_ref() {
+        Some(presence) if presence.track == track && is_current() => Presence {
+            state: truncate_field(state),
+            ..presence.clone()
+        },
+        _ => return Ok(()),
+    };
+
+    publish_locked(&mut current, presence)
+}
+
+/// Shows the idle presence used while playback is stopped.
+pub fn set_idle_activity() -> Result<()> {
+    let api = API.get().unwrap();
+
+    stop_lyrics();
+    stop_rotation();
+
+    let idle_text = api.conf_get_str(ConfigKey::IDLE_TEXT, ConfigDefault::IDLE_TEXT)?;
+    let idle_image = api.conf_get_str(ConfigKey::IDLE_IMAGE, ConfigDefault::IDLE_IMAGE)?;
+    let show_last_track =
+        api.conf_get_int(ConfigKey::IDLE_LAST_TRACK, ConfigDefault::IDLE_LAST_TRACK)? == 1;
+    // Keeps the activity type and member list text of the presence it replaces, or takes
+    // them from the settings when nothing was shown.
+    let shown = CURRENT_PRESENCE
+        .lock_recover()
+        .as_ref()
+        .map(|presence| (presence.activity_kind, presence.status_display));
+    let (activity_kind, status_display) = match shown {
+        Some(shown) => shown,
+        None => {
+            let profile = Profile::from_config()?;
+
+            (profile.activity_kind, profile.status_display)
+        }
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(Error::SystemTimeError)?
+        .as_secs() as i64;
+    let state = match LAST_PLAYED.lock_recover().as_ref() {
+        Some(last_played) if show_last_track => format!("Last played: {}", last_played),
+        _ => String::new(),
+    };
+
+    let presence = Presence {
+        details: idle_text,
+        state,
+        large_text: String::new(),
+        large_image: idle_image,
+        buttons: Vec::new(),
+        party: None,
+        start_timestamp: Some(now),
+        end_timestamp: None,
+        activity_kind,
+        status_display,
+        track: 0,
+        play: 0,
+        cover_key: None,
+        export: None,
+        track_fields: None,
+    };
+    let mut current = CURRENT_PRESENCE.lock_recover();
+
+    if worker::is_cancelled() {
+        return Err(Error::Cancelled);
     }
 
-    Ok(())
+    *current = None;
+    sink::publish_all(SinkEvent::Idle(&presence))
 }
 
-pub fn create_discord_client() -> Result<DiscordIpcClient> {
-    let client_id = API
-        .get()
-        .unwrap()
-        .conf_get_str(ConfigKey::CLIENT_ID, ConfigDefault::CLIENT_ID)?;
+/// Start/end timestamps for a track `elapsed` seconds in, ending after `length` seconds if known.
+fn activity_timestamps(now: i64, elapsed: f32, length: Option<f32>) -> (i64, Option<i64>) {
+    let start_timestamp = now.saturating_sub(elapsed as i64);
+
+    match length {
+        Some(length) if length > 0.0 => (
+            start_timestamp,
+            Some(start_timestamp.saturating_add(length.round() as i64)),
+        ),
+        _ => (start_timestamp, None),
+    }
+}
+
+pub fn create_discord_client() -> Result<DiscordConnection> {
+    let api = API.get().unwrap();
+
+    Ok(DiscordConnection::new(ConnectionSettings {
+        client_id: api.conf_get_str(ConfigKey::CLIENT_ID, ConfigDefault::CLIENT_ID)?,
+        socket: api.conf_get_str(ConfigKey::IPC_SOCKET, ConfigDefault::IPC_SOCKET)?,
+        broadcast: api.conf_get_int(ConfigKey::IPC_BROADCAST, ConfigDefault::IPC_BROADCAST)? == 1,
+        dry_run: api.conf_get_int(ConfigKey::DRY_RUN, ConfigDefault::DRY_RUN)? == 1,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        config::{CoverSource, Romanization},
+        deadbeef::testing::{self, Track},
+        util::{catch_panic, item_length},
+    };
+
+    /// Pieces random metadata and formats are assembled from: title formatting syntax, NULs,
+    /// multi-byte and combining characters.
+    const FRAGMENTS: &[&str] = &[
+        "%",
+        "%title%",
+        "%artist%",
+        "%album%",
+        "$",
+        "$if(",
+        "\\",
+        "\0",
+        "\"",
+        "'",
+        "(",
+        ")",
+        "[",
+        "]",
+        " - ",
+        "é",
+        "e\u{301}",
+        "ß",
+        "日本語",
+        "ひらがな",
+        "Русский",
+        "한국어",
+        "😀",
+        "\u{feff}",
+        "\u{200b}",
+        " ",
+        "\t",
+        "\n",
+        "abc",
+    ];
+    const LENGTHS: &[f32] = &[
+        -1.0,
+        0.0,
+        0.4,
+        245.5,
+        1e9,
+        f32::MAX,
+        f32::INFINITY,
+        f32::NAN,
+    ];
+    const SAMPLES: &[i64] = &[-1, 0, 441_000, 9_261_000, i64::MAX, i64::MIN];
+    const SAMPLERATES: &[&str] = &["", "0", "-44100", "44100", "1", "99999999999", "abc"];
+    const STATUSES: &[Status] = &[
+        Status::Paused,
+        Status::Songchanged,
+        Status::Seeked,
+        Status::Start,
+    ];
+
+    /// xorshift64*, so failures reproduce without pulling in a random number crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 >> 12;
+            self.0 ^= self.0 << 25;
+            self.0 ^= self.0 >> 27;
+            self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<T: Copy>(&mut self, values: &[T]) -> T {
+            values[self.below(values.len())]
+        }
+
+        fn text(&mut self, max_parts: usize) -> String {
+            let mut text = String::new();
+
+            for _ in 0..self.below(max_parts) {
+                if self.below(4) == 0 {
+                    // Raw bytes cut at arbitrary points, as broken tags decode to.
+                    let bytes = (0..self.below(8))
+                        .map(|_| self.next() as u8)
+                        .collect::<Vec<_>>();
+
+                    text.push_str(&String::from_utf8_lossy(&bytes));
+                } else {
+                    text.push_str(self.pick(FRAGMENTS));
+                }
+            }
 
-    Ok(DiscordIpcClient::new(&client_id))
+            text
+        }
+
+        fn track(&mut self) -> Track {
+            let mut meta = HashMap::new();
+
+            for key in ["title", "artist", "album"] {
+                meta.insert(key.to_string(), self.text(200));
+            }
+            meta.insert(
+                ":SAMPLERATE".to_string(),
+                self.pick(SAMPLERATES).to_string(),
+            );
+            if self.below(2) == 0 {
+                meta.insert(":URI".to_string(), "http://example.com/stream".to_string());
+            }
+
+            Track {
+                meta,
+                duration: self.pick(LENGTHS),
+                start_sample: self.pick(SAMPLES),
+                end_sample: self.pick(SAMPLES),
+                position: self.pick(LENGTHS),
+                paused: self.below(2) == 0,
+            }
+        }
+    }
+
+    #[test]
+    fn random_metadata_never_panics_while_formatting() {
+        let _api = testing::setup();
+        let api = API.get().unwrap();
+        let mut rng = Rng(0x5eed_1234_abcd_ef01);
+
+        // Keeps the lookup off the network.
+        testing::set_conf(ConfigKey::COVER_SOURCE, CoverSource::NoCover as i32);
+        testing::set_conf(
+            ConfigKey::CLEANUP_PRESENCE_PRESETS,
+            "remaster, explicit, feat, live, brackets",
+        );
+        for key in [
+            ConfigKey::ROMANIZE_DETAILS,
+            ConfigKey::ROMANIZE_STATE,
+            ConfigKey::ROMANIZE_ICON_TEXT,
+        ] {
+            testing::set_conf(key, Romanization::Both as i32);
+        }
+
+        for _ in 0..1_000 {
+            for key in [
+                ConfigKey::TITLE_SCRIPT,
+                ConfigKey::STATE_SCRIPT,
+                ConfigKey::ICON_SCRIPT,
+            ] {
+                testing::set_conf(key, rng.text(20));
+            }
+            testing::set_conf(ConfigKey::END_TIMESTAMP2, rng.below(2));
+
+            let hide_on_pause = rng.below(2) == 1;
+            let track = rng.track();
+            let status = rng.pick(STATUSES);
+
+            testing::set_conf(ConfigKey::HIDE_ON_PAUSE, hide_on_pause as i32);
+            testing::play(track.clone());
+            *LAST_PLAYED.lock_recover() = None;
+
+            // The same way the exported entry points run it.
+            let finished = catch_panic("update_activity", false, || {
+                let nextitem_length = api
+                    .streamer_get_playing_track()
+                    .and_then(|item| item_length(&item))
+                    .ok()
+                    .flatten();
+
+                update_activity(status, nextitem_length).ok();
+                true
+            });
+
+            assert!(finished, "panicked on {:?} with {:?}", status, track);
+            // Only a seek while paused with hide on pause ends before the texts are formatted.
+            assert!(
+                LAST_PLAYED.lock_recover().is_some()
+                    || (hide_on_pause && status == Status::Seeked && track.paused),
+                "stopped early on {:?} with {:?}",
+                status,
+                track
+            );
+        }
+    }
+
+    #[test]
+    fn timestamps_with_length_end_after_the_track() {
+        assert_eq!(
+            activity_timestamps(10_000, 30.7, Some(200.4)),
+            (9_970, Some(10_170))
+        );
+    }
+
+    #[test]
+    fn timestamps_without_length_only_count_up() {
+        assert_eq!(activity_timestamps(10_000, 30.0, None), (9_970, None));
+        assert_eq!(activity_timestamps(10_000, 0.0, Some(0.0)), (10_000, None));
+        assert_eq!(activity_timestamps(10_000, 0.0, Some(-1.0)), (10_000, None));
+    }
 }
@@ -0,0 +1,130 @@
+use std::{fs::OpenOptions, io::Write};
+
+use discord_rich_presence::activity::Activity;
+
+use crate::{
+    API,
+    config::{ConfigDefault, ConfigKey},
+    discordrpc::Presence,
+    error::{Error, Result},
+    sink::PresenceSink,
+    status,
+    util::plugin_config_dir,
+};
+
+/// The JSON `set_activity` would send for `activity`, `null` for a cleared activity.
+pub fn activity_payload(activity: Option<&Activity>) -> String {
+    serde_json::to_string(&activity).unwrap_or_default()
+}
+
+/// Logs the activity as JSON, and appends it to the dry run file, instead of sending it.
+pub struct DryRunSink;
+
+impl DryRunSink {
+    fn write(&self, activity: Option<&Activity>) -> Result<()> {
+        let api = API.get().unwrap();
+        let payload = activity_payload(activity);
+        let path = api.conf_get_str(ConfigKey::DRY_RUN_FILE, ConfigDefault::DRY_RUN_FILE)?;
+
+        // Whoever turns on a dry run wants to see it, whatever the log level.
+        api.log_always(format!("Dry run activity: {}", payload));
+        status::set_last_activity(activity.map(|_| payload.clone()));
+
+        // Relative paths are kept next to the plugin's other files.
+        if !path.trim().is_empty() {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(plugin_config_dir()?.join(path.trim()))
+                .map_err(Error::Io)?;
+
+            writeln!(file, "{}", payload).map_err(Error::Io)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PresenceSink for DryRunSink {
+    fn name(&self) -> &'static str {
+        "dry run"
+    }
+
+    fn enabled(&self) -> Result<bool> {
+        let api = API.get().unwrap();
+
+        Ok(api.conf_get_int(ConfigKey::DRY_RUN, ConfigDefault::DRY_RUN)? == 1)
+    }
+
+    fn publish(&mut self, presence: &Presence) -> Result<()> {
+        self.write(Some(&presence.activity()))
+    }
+
+    fn idle(&mut self, presence: &Presence) -> Result<()> {
+        self.write(Some(&presence.activity()))
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.write(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::deadbeef::testing::presence;
+
+    fn payload(presence: &Presence) -> Value {
+        serde_json::from_str(&activity_payload(Some(&presence.activity()))).unwrap()
+    }
+
+    #[test]
+    fn presence_payload() {
+        assert_eq!(
+            payload(&presence(1)),
+            json!({
+                "details": "Song",
+                "state": "Artist",
+                "timestamps": { "start": 1000, "end": 1200 },
+                "assets": {
+                    "large_image": "https://example.com/cover.jpg",
+                    "large_text": "Album",
+                },
+                "party": { "id": "album", "size": [3, 12] },
+                "buttons": [{ "label": "Listen", "url": "https://example.com/" }],
+                "type": 2,
+                "status_display_type": 2,
+            })
+        );
+    }
+
+    #[test]
+    fn empty_fields_are_left_out() {
+        let presence = Presence {
+            state: String::new(),
+            large_text: String::new(),
+            buttons: Vec::new(),
+            party: None,
+            end_timestamp: None,
+            ..presence(1)
+        };
+
+        assert_eq!(
+            payload(&presence),
+            json!({
+                "details": "Song",
+                "timestamps": { "start": 1000 },
+                "assets": { "large_image": "https://example.com/cover.jpg" },
+                "type": 2,
+                "status_display_type": 2,
+            })
+        );
+    }
+
+    #[test]
+    fn cleared_activity_is_null() {
+        assert_eq!(activity_payload(None), "null");
+    }
+}
@@ -11,7 +11,22 @@ pub enum Error {
     SystemTimeError(std::time::SystemTimeError),
     FromBytesUntilNulError(FromBytesUntilNulError),
     InvalidCoverSource,
+    InvalidCoverSize,
     JsonParseFailed(json::Error),
     HttpGetFailed(String),
     MusicbrainzNoReleaseFound,
+    Cancelled,
+    InvalidLogLevel,
+    Io(std::io::Error),
+    ArtworkPluginMissing,
+    ArtworkNotFound,
+    ImageFailed(image::ImageError),
+    InvalidUploadMethod,
+    InvalidActivityKind,
+    InvalidStatusDisplay,
+    InvalidRomanization,
+    InvalidPartyMode,
+    UploaderDisabled,
+    HttpPostFailed(String),
+    UploadResponseMissingUrl,
 }
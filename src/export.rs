@@ -0,0 +1,122 @@
+use std::{fs, path::Path};
+
+use json::object;
+
+use crate::{
+    API,
+    config::{ConfigDefault, ConfigKey},
+    discordrpc::Presence,
+    error::{Error, Result},
+    sink::PresenceSink,
+    util::{nowplaying_format_string, plugin_config_dir},
+};
+
+/// The export templates rendered against the playing track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportTemplates {
+    /// Added to the JSON file as `text`.
+    json_text: String,
+    /// The whole content of the text file.
+    text: String,
+}
+
+impl ExportTemplates {
+    /// `None` while the export is disabled.
+    pub fn render() -> Result<Option<Self>> {
+        let api = API.get().unwrap();
+
+        if api.conf_get_int(ConfigKey::EXPORT_ENABLE, ConfigDefault::EXPORT_ENABLE)? == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            json_text: nowplaying_format_string(&api.conf_get_str(
+                ConfigKey::EXPORT_JSON_SCRIPT,
+                ConfigDefault::EXPORT_JSON_SCRIPT,
+            )?)?,
+            text: nowplaying_format_string(&api.conf_get_str(
+                ConfigKey::EXPORT_TEXT_SCRIPT,
+                ConfigDefault::EXPORT_TEXT_SCRIPT,
+            )?)?,
+        }))
+    }
+}
+
+/// The presence's cover if it is a URL; Discord asset keys mean nothing to an overlay.
+fn cover_url(presence: &Presence) -> Option<&str> {
+    presence
+        .large_image
+        .starts_with("https://")
+        .then_some(presence.large_image.as_str())
+}
+
+/// Replaces `path` in one step so overlays never read a half-written file.
+fn write_atomic(path: &Path, content: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+
+    tmp.push(".tmp");
+    fs::write(&tmp, content).map_err(Error::Io)?;
+    fs::rename(&tmp, path).map_err(Error::Io)
+}
+
+/// Writes the now playing JSON and text files for stream overlays.
+pub struct FileSink;
+
+impl PresenceSink for FileSink {
+    fn name(&self) -> &'static str {
+        "export"
+    }
+
+    fn enabled(&self) -> Result<bool> {
+        let api = API.get().unwrap();
+
+        Ok(api.conf_get_int(ConfigKey::EXPORT_ENABLE, ConfigDefault::EXPORT_ENABLE)? == 1)
+    }
+
+    fn publish(&mut self, presence: &Presence) -> Result<()> {
+        write_now_playing(Some(presence))
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        write_now_playing(None)
+    }
+}
+
+/// Writes the JSON and text files for `presence`, or empties them when nothing is shown.
+fn write_now_playing(presence: Option<&Presence>) -> Result<()> {
+    let api = API.get().unwrap();
+    let json_file =
+        api.conf_get_str(ConfigKey::EXPORT_JSON_FILE, ConfigDefault::EXPORT_JSON_FILE)?;
+    let text_file =
+        api.conf_get_str(ConfigKey::EXPORT_TEXT_FILE, ConfigDefault::EXPORT_TEXT_FILE)?;
+    let (json, text) =
+        match presence.and_then(|presence| Some((presence, presence.export.as_ref()?))) {
+            Some((presence, templates)) => (
+                object! {
+                    playing: true,
+                    details: presence.details.as_str(),
+                    state: presence.state.as_str(),
+                    large_text: presence.large_text.as_str(),
+                    cover_url: cover_url(presence),
+                    start_timestamp: presence.start_timestamp,
+                    end_timestamp: presence.end_timestamp,
+                    text: templates.json_text.as_str(),
+                },
+                templates.text.clone(),
+            ),
+            _ => (object! { playing: false }, String::new()),
+        };
+
+    // Relative paths are kept next to the plugin's other files.
+    if !json_file.trim().is_empty() {
+        write_atomic(
+            &plugin_config_dir()?.join(json_file.trim()),
+            &json.pretty(2),
+        )?;
+    }
+    if !text_file.trim().is_empty() {
+        write_atomic(&plugin_config_dir()?.join(text_file.trim()), &text)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,338 @@
+#[cfg(unix)]
+use std::{collections::HashSet, env};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use discord_rich_presence::{DiscordIpc, activity::Activity, error::Error as IpcError};
+use serde_json::json;
+
+use crate::API;
+
+#[cfg(unix)]
+type Stream = std::os::unix::net::UnixStream;
+#[cfg(windows)]
+type Stream = fs::File;
+
+type IpcResult<T> = std::result::Result<T, IpcError>;
+
+/// Directories Discord may put its socket in, from the environment.
+#[cfg(unix)]
+const ENV_KEYS: [&str; 4] = ["XDG_RUNTIME_DIR", "TMPDIR", "TMP", "TEMP"];
+
+/// Where sandboxed and alternative Discord builds put their socket, relative to those directories.
+#[cfg(unix)]
+const APP_SUBPATHS: [&str; 10] = [
+    "",
+    "app/com.discordapp.Discord/",
+    "app/com.discordapp.DiscordCanary/",
+    "app/dev.vencord.Vesktop/",
+    ".flatpak/com.discordapp.Discord/xdg-run/",
+    ".flatpak/com.discordapp.DiscordCanary/xdg-run/",
+    ".flatpak/dev.vencord.Vesktop/xdg-run/",
+    "snap.discord/",
+    "snap.discord-canary/",
+    "snap.discord-ptb/",
+];
+
+/// Discord clients number their sockets `discord-ipc-0` to `discord-ipc-9`.
+const SOCKET_INDICES: std::ops::Range<u8> = 0..10;
+/// How long a hung Discord client may block a read or write.
+#[cfg(unix)]
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+/// Publishing while Discord is gone reconnects at most this often.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Everything that decides which Discord clients the plugin talks to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionSettings {
+    pub client_id: String,
+    /// Empty to pick the first socket found, a socket index (0-9), or a socket path.
+    pub socket: String,
+    /// Send the presence to every Discord client found instead of only the first.
+    pub broadcast: bool,
+    /// Don't connect at all; the dry run sink logs the activities instead.
+    pub dry_run: bool,
+}
+
+/// Every existing Discord IPC socket, in the order they are tried.
+#[cfg(unix)]
+fn socket_candidates() -> Vec<PathBuf> {
+    let mut bases = Vec::new();
+
+    for key in ENV_KEYS {
+        if let Ok(value) = env::var(key) {
+            bases.push(PathBuf::from(&value));
+
+            // Inside a snap, XDG_RUNTIME_DIR points at the snap's own subdirectory.
+            if key == "XDG_RUNTIME_DIR"
+                && env::var("SNAP").is_ok()
+                && let Some(parent) = PathBuf::from(value).parent()
+            {
+                bases.push(parent.to_path_buf());
+            }
+        }
+    }
+    bases.push(PathBuf::from("/tmp"));
+
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+
+    for index in SOCKET_INDICES {
+        for base in &bases {
+            for subpath in APP_SUBPATHS {
+                let path = base.join(subpath).join(format!("discord-ipc-{}", index));
+
+                if path.exists() && seen.insert(fs::canonicalize(&path).unwrap_or(path.clone())) {
+                    candidates.push(path);
+                }
+            }
+        }
+    }
+
+    candidates
+}
+
+/// Windows named pipes can't be listed cheaply, so every index is a candidate.
+#[cfg(windows)]
+fn socket_candidates() -> Vec<PathBuf> {
+    SOCKET_INDICES
+        .map(|index| PathBuf::from(format!(r"\\?\pipe\discord-ipc-{}", index)))
+        .collect()
+}
+
+#[cfg(unix)]
+fn open_stream(path: &Path) -> io::Result<Stream> {
+    let stream = Stream::connect(path)?;
+
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    Ok(stream)
+}
+
+#[cfg(windows)]
+fn open_stream(path: &Path) -> io::Result<Stream> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    // GENERIC_READ | GENERIC_WRITE; pipes opened as files can't be given timeouts.
+    fs::OpenOptions::new().access_mode(0x3).open(path)
+}
+
+/// Sockets to try for the `socket` setting: a pinned index or path, or every candidate.
+fn pinned_candidates(socket: &str) -> Vec<PathBuf> {
+    let socket = socket.trim();
+
+    if socket.is_empty() {
+        socket_candidates()
+    } else if let Ok(index) = socket.parse::<u8>() {
+        let name = format!("discord-ipc-{}", index);
+
+        socket_candidates()
+            .into_iter()
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|file_name| *file_name == *name)
+            })
+            .collect()
+    } else {
+        vec![PathBuf::from(socket)]
+    }
+}
+
+/// IPC client bound to one socket path, unlike the library client which always takes the first.
+struct IpcSocket {
+    client_id: String,
+    path: PathBuf,
+    stream: Option<Stream>,
+    /// Discord account the client is logged in as, from its READY reply.
+    user: Option<String>,
+}
+
+impl DiscordIpc for IpcSocket {
+    fn connect_ipc(&mut self) -> IpcResult<()> {
+        self.stream = Some(open_stream(&self.path).map_err(|_| IpcError::IPCConnectionFailed)?);
+
+        Ok(())
+    }
+
+    fn send_handshake(&mut self) -> IpcResult<()> {
+        self.send(json!({ "v": 1, "client_id": self.client_id }), 0)?;
+
+        let (_, ready) = self.recv()?;
+        let user = &ready["data"]["user"];
+
+        self.user = user["global_name"]
+            .as_str()
+            .or(user["username"].as_str())
+            .map(str::to_string);
+
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> IpcResult<()> {
+        let stream = self.stream.as_mut().ok_or(IpcError::NotConnected)?;
+
+        stream.write_all(data).map_err(IpcError::WriteError)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> IpcResult<()> {
+        let stream = self.stream.as_mut().ok_or(IpcError::NotConnected)?;
+
+        stream.read_exact(buffer).map_err(IpcError::ReadError)
+    }
+
+    fn close(&mut self) -> IpcResult<()> {
+        self.send(json!({}), 2).ok();
+
+        let mut stream = self.stream.take().ok_or(IpcError::NotConnected)?;
+
+        stream.flush().map_err(IpcError::FlushError)
+    }
+
+    fn get_client_id(&self) -> &str {
+        &self.client_id
+    }
+}
+
+/// The Discord clients the presence is sent to: the first one found, a pinned one, or all.
+pub struct DiscordConnection {
+    settings: ConnectionSettings,
+    sockets: Vec<IpcSocket>,
+    last_attempt: Option<Instant>,
+}
+
+impl DiscordConnection {
+    pub fn new(settings: ConnectionSettings) -> Self {
+        Self {
+            settings,
+            sockets: Vec::new(),
+            last_attempt: None,
+        }
+    }
+
+    pub fn settings(&self) -> &ConnectionSettings {
+        &self.settings
+    }
+
+    /// Connected sockets with the Discord user behind each, for the status report.
+    pub fn clients(&self) -> Vec<String> {
+        if self.settings.dry_run {
+            return vec!["dry run, not connected to Discord".to_string()];
+        }
+
+        self.sockets
+            .iter()
+            .map(|socket| match &socket.user {
+                Some(user) => format!("{} as {}", socket.path.display(), user),
+                None => socket.path.display().to_string(),
+            })
+            .collect()
+    }
+
+    pub fn connect(&mut self) -> IpcResult<()> {
+        let api = API.get().unwrap();
+
+        if self.settings.dry_run {
+            api.log_info("Dry run: activities are logged instead of sent to Discord.".to_string());
+            return Ok(());
+        }
+
+        self.last_attempt = Some(Instant::now());
+
+        let candidates = pinned_candidates(&self.settings.socket);
+
+        if candidates.is_empty() {
+            return Err(IpcError::IPCNotFound);
+        }
+
+        for path in candidates {
+            let mut socket = IpcSocket {
+                client_id: self.settings.client_id.clone(),
+                path,
+                stream: None,
+                user: None,
+            };
+
+            match socket.connect() {
+                Ok(()) => {
+                    api.log_info(format!(
+                        "Connected to Discord at {}.",
+                        socket.path.display()
+                    ));
+                    self.sockets.push(socket);
+
+                    if !self.settings.broadcast {
+                        break;
+                    }
+                }
+                Err(e) => api.log_debug(format!(
+                    "Could not connect to Discord at {}: {:?}",
+                    socket.path.display(),
+                    e
+                )),
+            }
+        }
+
+        if self.sockets.is_empty() {
+            Err(IpcError::IPCConnectionFailed)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Drops whatever sockets are left and connects again, e.g. after Discord restarted.
+    /// Fails without trying if the last attempt was less than [`RECONNECT_INTERVAL`] ago.
+    pub fn reconnect(&mut self) -> IpcResult<()> {
+        if self.settings.dry_run {
+            return Ok(());
+        }
+
+        if self
+            .last_attempt
+            .is_some_and(|last_attempt| last_attempt.elapsed() < RECONNECT_INTERVAL)
+        {
+            return Err(IpcError::NotConnected);
+        }
+
+        self.close().ok();
+        self.connect()
+    }
+
+    /// Runs `f` on every connected socket, succeeding if at least one of them did.
+    fn for_each(&mut self, mut f: impl FnMut(&mut IpcSocket) -> IpcResult<()>) -> IpcResult<()> {
+        let mut result = Err(IpcError::NotConnected);
+
+        for socket in &mut self.sockets {
+            match f(socket) {
+                Ok(()) => result = Ok(()),
+                Err(e) if result.is_err() => result = Err(e),
+                Err(_) => {}
+            }
+        }
+
+        result
+    }
+
+    pub fn set_activity(&mut self, activity: Activity) -> IpcResult<()> {
+        self.for_each(|socket| socket.set_activity(activity.clone()))
+    }
+
+    pub fn clear_activity(&mut self) -> IpcResult<()> {
+        self.for_each(|socket| socket.clear_activity())
+    }
+
+    pub fn close(&mut self) -> IpcResult<()> {
+        if self.settings.dry_run {
+            return Ok(());
+        }
+
+        let result = self.for_each(|socket| socket.close());
+
+        self.sockets.clear();
+        result
+    }
+}
@@ -1,65 +1,118 @@
+mod actions;
+mod artwork;
+mod cleanup;
 mod config;
+mod cover;
+mod curl;
 mod deadbeef;
 mod discordrpc;
+mod dryrun;
 mod error;
+mod export;
+mod ipc;
+mod listenbrainz;
+mod lyrics;
 mod musicbrainz;
+mod overrides;
+mod profile;
+mod romanize;
+mod rotation;
+mod server;
+mod sink;
+mod status;
+mod upload;
 mod util;
+mod webhook;
+mod worker;
 
 use std::{
-    ffi::c_void,
-    mem,
-    ptr::{self, null_mut},
-    sync::{Arc, Mutex},
+    mem, ptr,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
 };
 
-use discord_rich_presence::{DiscordIpc, DiscordIpcClient};
 use lazy_static::lazy_static;
 use once_cell::sync::OnceCell;
 
 use crate::{
+    actions::ACTIONS,
     config::*,
+    cover::prefetch_next_cover,
     deadbeef::{
         DB_EV_CONFIGCHANGED, DB_EV_PAUSED, DB_EV_SEEKED, DB_EV_SONGCHANGED, DB_EV_STOP,
-        DB_functions_t, DB_misc_t, DB_plugin_t, ddb_event_trackchange_t,
-        ddb_playback_state_e_DDB_PLAYBACK_STATE_STOPPED, safe_wrapper::SafeDBPlayItem,
+        DB_EV_TERMINATE, DB_functions_t, DB_misc_t, DB_playItem_t, DB_plugin_action_t, DB_plugin_t,
+        ddb_event_trackchange_t, ddb_playback_state_e_DDB_PLAYBACK_STATE_STOPPED,
+        safe_wrapper::SafeDBPlayItem,
+    },
+    discordrpc::{
+        Status, clear_activity, create_discord_client, set_idle_activity, update_activity,
     },
-    discordrpc::{Status, clear_activity, create_discord_client, update_activity},
     error::{Error, Result},
+    ipc::{ConnectionSettings, DiscordConnection},
+    util::{MutexExt, catch_panic, item_length},
 };
 
 static API: OnceCell<&DB_functions_t> = OnceCell::new();
+/// Bumped on every playback event so delayed work can tell whether it is stale.
+static EVENT_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// How long shutdown waits for in-flight workers before closing the connection anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
 lazy_static! {
-    static ref DRPC: Mutex<Option<DiscordIpcClient>> = Mutex::new(None);
-    static ref DISCORD_CLIENT_ID: Mutex<Option<String>> = Mutex::new(None);
+    static ref DRPC: Mutex<Option<DiscordConnection>> = Mutex::new(None);
+    static ref CONNECTION_SETTINGS: Mutex<Option<ConnectionSettings>> = Mutex::new(None);
 }
 
 fn config_update() -> Result<()> {
-    let mut drpc = DRPC.lock().unwrap();
+    let mut drpc = DRPC.lock_recover();
     let api = API.get().unwrap();
     let enable = api.conf_get_int(ConfigKey::ENABLE, ConfigDefault::ENABLE)?;
-    let client_id = api.conf_get_str(ConfigKey::CLIENT_ID, ConfigDefault::CLIENT_ID)?;
+    let log_level =
+        LogLevel::try_from(api.conf_get_int(ConfigKey::LOG_LEVEL, ConfigDefault::LOG_LEVEL)?)
+            .unwrap_or(LogLevel::Warn);
+    let log_to_file = api.conf_get_int(ConfigKey::LOG_FILE, ConfigDefault::LOG_FILE)? == 1;
+
+    api.set_log_config(log_level, log_to_file);
+
+    // Independent of Discord, so a missing Discord client doesn't keep the server down.
+    if let Err(e) = server::configure() {
+        api.log_error(format!("Failed to start the now playing server: {:?}", e));
+        status::record_error("server", &e);
+    }
+
+    let new_client = create_discord_client()?;
 
-    if let Some(id) = DISCORD_CLIENT_ID.lock().unwrap().as_ref()
-        && (id != &client_id || enable == 0)
+    if let Some(settings) = CONNECTION_SETTINGS.lock_recover().as_ref()
+        && (settings != new_client.settings() || enable == 0)
         && let Some(mut client) = drpc.take()
     {
-        api.trace(format!(
-            "Disconnecting from Discord RPC (client ID changed from {} to {}).",
-            id, client_id
+        api.log_info(format!(
+            "Disconnecting from Discord RPC (settings changed from {:?} to {:?}).",
+            settings,
+            new_client.settings()
         ));
+        status::set_clients(Vec::new());
         client.close().map_err(Error::DiscordFailed)?;
     }
 
-    if drpc.is_none()
-        && let Ok(mut client) = create_discord_client()
-        && enable == 1
-    {
-        api.trace(format!(
+    if drpc.is_none() && enable == 1 {
+        let mut client = new_client;
+
+        api.log_info(format!(
             "Connecting to Discord RPC with client ID {}.",
-            client_id
+            client.settings().client_id
         ));
-        client.connect().unwrap();
-        *DISCORD_CLIENT_ID.lock().unwrap() = Some(client_id);
+        // Kept either way; publishing retries the connection until Discord is running.
+        if let Err(e) = client.connect() {
+            let e = Error::DiscordFailed(e);
+
+            api.log_warn(format!("Discord RPC isn't reachable yet: {:?}", e));
+            status::record_error("connection", &e);
+        }
+        status::set_clients(client.clients());
+        *CONNECTION_SETTINGS.lock_recover() = Some(client.settings().clone());
         *drpc = Some(client);
     }
 
@@ -67,182 +120,298 @@ fn config_update() -> Result<()> {
         && let Some(output) = unsafe { api.get_output()?.as_ref() }
         && output.state()? != ddb_playback_state_e_DDB_PLAYBACK_STATE_STOPPED
     {
-        let data = Arc::new(UpdateThreadData {
-            status: Status::Seeked,
-            nextitem_length: None,
-        });
-        let raw_ptr = Arc::into_raw(data);
-        if let Err(e) = api.thread_start(create_update_thread, raw_ptr as *mut c_void) {
-            unsafe { Arc::from_raw(raw_ptr) };
-            return Err(e);
-        }
+        spawn_update(Status::Seeked, None)?;
     }
 
     Ok(())
 }
 
-#[repr(C)]
 struct UpdateThreadData {
     status: Status,
     nextitem_length: Option<f32>,
 }
 
-#[unsafe(no_mangle)]
-extern "C" fn create_update_thread(ptr: *mut c_void) {
+fn spawn_update(status: Status, nextitem_length: Option<f32>) -> Result<()> {
+    worker::spawn_with(
+        "create_update_thread",
+        create_update_thread,
+        UpdateThreadData {
+            status,
+            nextitem_length,
+        },
+    )
+}
+
+fn create_update_thread(data: UpdateThreadData) {
     let api = API.get().unwrap();
-    let data = unsafe { Arc::from_raw(ptr as *mut UpdateThreadData) };
 
-    api.trace(format!("Updating Discord activity: {:?}", data.status));
+    api.log_debug(format!("Updating Discord activity: {:?}", data.status));
 
     if let Err(e) = update_activity(data.status, data.nextitem_length) {
-        api.trace(format!("Failed to update Discord activity: {:?}", e));
+        api.log_warn(format!("Failed to update Discord activity: {:?}", e));
+        status::record_error("presence", &e);
+    }
+
+    // Runs after the current cover so it never delays the presence update.
+    if let Status::Songchanged = data.status
+        && let Err(e) = prefetch_next_cover()
+    {
+        api.log_debug(format!("Failed to prefetch the next cover: {:?}", e));
     }
 }
 
-#[unsafe(no_mangle)]
-extern "C" fn clear_activity_thread(_: *mut c_void) {
+fn spawn_clear() -> bool {
+    worker::spawn_with("clear_activity_thread", clear_activity_thread, ()).is_ok()
+}
+
+fn clear_activity_thread(_: ()) {
     let api = API.get().unwrap();
 
-    api.trace("Clearing Discord activity from thread.".to_string());
+    api.log_debug("Clearing Discord activity from thread.".to_string());
 
     if let Err(e) = clear_activity() {
-        api.trace(format!("Failed to clear Discord activity: {:?}", e));
+        api.log_warn(format!("Failed to clear Discord activity: {:?}", e));
+        status::record_error("presence", &e);
     }
 }
 
+fn idle_activity_thread(_: ()) {
+    let api = API.get().unwrap();
+
+    api.log_debug("Setting idle Discord activity from thread.".to_string());
+
+    if let Err(e) = set_idle_activity() {
+        api.log_warn(format!("Failed to set idle Discord activity: {:?}", e));
+        status::record_error("presence", &e);
+    }
+}
+
+struct ClearTimerData {
+    generation: u64,
+    timeout: Duration,
+}
+
+/// Clears the activity once `timeout` has passed, unless another playback event arrived meanwhile.
+fn clear_timer_thread(data: ClearTimerData) {
+    let api = API.get().unwrap();
+
+    if !worker::sleep_while(data.timeout, || {
+        EVENT_GENERATION.load(Ordering::SeqCst) == data.generation
+    }) {
+        return;
+    }
+
+    api.log_info(format!(
+        "Clearing Discord activity after {} seconds without playback changes.",
+        data.timeout.as_secs()
+    ));
+
+    if let Err(e) = clear_activity() {
+        api.log_warn(format!("Failed to clear Discord activity: {:?}", e));
+        status::record_error("presence", &e);
+    }
+}
+
+fn start_clear_timer(generation: u64, minutes: i32) -> bool {
+    let data = ClearTimerData {
+        generation,
+        timeout: Duration::from_secs(minutes as u64 * 60),
+    };
+
+    worker::spawn_with("clear_timer_thread", clear_timer_thread, data).is_ok()
+}
+
 #[unsafe(no_mangle)]
 extern "C" fn message(id: u32, ctx: usize, p1: u32, _: u32) -> i32 {
-    let api = API.get().unwrap();
+    let ret = catch_panic("message", false, || handle_message(id, ctx, p1));
+
+    if ret { 1 } else { -1 }
+}
+
+fn handle_message(id: u32, ctx: usize, p1: u32) -> bool {
+    let Some(api) = API.get() else {
+        return false;
+    };
     let enable = api.conf_get_int(ConfigKey::ENABLE, ConfigDefault::ENABLE);
     let hide_on_pause = api.conf_get_int(ConfigKey::HIDE_ON_PAUSE, ConfigDefault::HIDE_ON_PAUSE);
     let ctx = unsafe { (ctx as *mut ddb_event_trackchange_t).as_ref() };
+    let generation = match id {
+        DB_EV_SONGCHANGED if ctx.is_none_or(|ctx| ctx.to.is_null()) => {
+            EVENT_GENERATION.load(Ordering::SeqCst)
+        }
+        DB_EV_SONGCHANGED | DB_EV_SEEKED | DB_EV_PAUSED | DB_EV_STOP => {
+            EVENT_GENERATION.fetch_add(1, Ordering::SeqCst) + 1
+        }
+        _ => EVENT_GENERATION.load(Ordering::SeqCst),
+    };
 
-    api.trace(format!(
+    api.log_debug(format!(
         "message received: id={}, ctx={:?}, p1={}",
         id, &ctx, p1
     ));
 
-    let ret = match id {
+    match id {
         DB_EV_CONFIGCHANGED => {
             if let Err(e) = config_update() {
-                api.trace(format!("Failed to update config: {:?}", e));
+                api.log_error(format!("Failed to update config: {:?}", e));
+                status::record_error("connection", &e);
                 true
             } else {
                 false
             }
         }
+        // Stopping sends a song change to no track; DB_EV_STOP takes care of the presence.
+        DB_EV_SONGCHANGED if ctx.is_some_and(|ctx| ctx.to.is_null()) => true,
         DB_EV_SONGCHANGED => {
             if let Ok(enable) = enable
                 && enable == 1
                 && let Some(ctx) = ctx
             {
                 let playlist_item = SafeDBPlayItem::new(ctx.to);
-                let nextitem_length = api.pl_get_item_duration(&playlist_item).ok();
-                let data = Arc::new(UpdateThreadData {
-                    status: Status::Songchanged,
-                    nextitem_length,
-                });
-                let raw_ptr = Arc::into_raw(data);
+                let nextitem_length = item_length(&playlist_item).ok().flatten();
 
                 mem::forget(playlist_item);
-                match api.thread_start(create_update_thread, raw_ptr as *mut c_void) {
-                    Ok(_) => true,
-                    Err(_) => {
-                        unsafe { Arc::from_raw(raw_ptr) };
-                        false
-                    }
-                }
+                spawn_update(Status::Songchanged, nextitem_length).is_ok()
             } else {
-                api.thread_start(clear_activity_thread, null_mut())
-                    .ok()
-                    .is_some()
+                spawn_clear()
             }
         }
         DB_EV_SEEKED => {
             if let Ok(enable) = enable
                 && enable == 1
             {
-                let data = Arc::new(UpdateThreadData {
-                    status: Status::Seeked,
-                    nextitem_length: None,
-                });
-                let raw_ptr = Arc::into_raw(data);
-
-                match api.thread_start(create_update_thread, raw_ptr as *mut c_void) {
-                    Ok(_) => true,
-                    Err(_) => {
-                        unsafe { Arc::from_raw(raw_ptr) };
-                        false
-                    }
-                }
+                spawn_update(Status::Seeked, None).is_ok()
             } else {
                 true
             }
         }
-        DB_EV_PAUSED if enable.is_ok() && *enable.as_ref().ok().unwrap() == 1 => {
+        DB_EV_PAUSED if enable.as_ref().is_ok_and(|enable| *enable == 1) => {
             if let Ok(hide_on_pause) = hide_on_pause
                 && !(hide_on_pause == 1 && p1 == 1)
             {
-                let data = Arc::new(UpdateThreadData {
-                    status: if p1 == 1 {
-                        Status::Paused
-                    } else {
-                        Status::Start
-                    },
-                    nextitem_length: None,
-                });
-                let raw_ptr = Arc::into_raw(data);
-                match api.thread_start(create_update_thread, raw_ptr as *mut c_void) {
-                    Ok(_) => true,
-                    Err(_) => {
-                        unsafe { Arc::from_raw(raw_ptr) };
-                        false
-                    }
+                let status = if p1 == 1 {
+                    Status::Paused
+                } else {
+                    Status::Start
+                };
+                let started = spawn_update(status, None).is_ok();
+
+                if started
+                    && p1 == 1
+                    && let Ok(pause_timeout) =
+                        api.conf_get_int(ConfigKey::PAUSE_TIMEOUT, ConfigDefault::PAUSE_TIMEOUT)
+                    && pause_timeout > 0
+                {
+                    start_clear_timer(generation, pause_timeout);
                 }
+
+                started
             } else {
-                api.thread_start(clear_activity_thread, null_mut())
-                    .ok()
-                    .is_some()
+                spawn_clear()
             }
         }
         DB_EV_STOP => {
             if let Ok(enable) = enable
                 && enable == 1
             {
-                api.thread_start(clear_activity_thread, null_mut())
-                    .ok()
-                    .is_some()
+                if let Ok(idle_enable) =
+                    api.conf_get_int(ConfigKey::IDLE_ENABLE, ConfigDefault::IDLE_ENABLE)
+                    && idle_enable == 1
+                {
+                    let started =
+                        worker::spawn_with("idle_activity_thread", idle_activity_thread, ())
+                            .is_ok();
+
+                    if started
+                        && let Ok(idle_timeout) =
+                            api.conf_get_int(ConfigKey::IDLE_TIMEOUT, ConfigDefault::IDLE_TIMEOUT)
+                        && idle_timeout > 0
+                    {
+                        start_clear_timer(generation, idle_timeout);
+                    }
+
+                    started
+                } else {
+                    spawn_clear()
+                }
             } else {
                 true
             }
         }
+        DB_EV_TERMINATE => {
+            shutdown();
+            true
+        }
         _ => false,
+    }
+}
+
+/// Cancels pending work, clears the presence and closes the Discord connection.
+fn shutdown() {
+    let Some(api) = API.get() else {
+        return;
     };
 
-    if ret { 1 } else { -1 }
+    // Before cancelling, so the sinks can still tell their listeners that playback ended.
+    api.log_info("Clearing activity before shutdown.".to_string());
+    clear_activity().ok();
+
+    worker::cancel();
+    EVENT_GENERATION.fetch_add(1, Ordering::SeqCst);
+    api.abort_http_requests();
+
+    let mut client = DRPC.lock_recover().take();
+
+    // Again, in case an update that was already running got through in between.
+    if let Some(client) = client.as_mut() {
+        client.clear_activity().ok();
+    }
+
+    if !worker::wait_idle(SHUTDOWN_TIMEOUT) {
+        api.log_warn("Timed out waiting for Discord RPC workers to finish.".to_string());
+    }
+
+    if let Some(mut client) = client {
+        api.log_info("Closing Discord RPC connection.".to_string());
+        client.close().ok();
+    }
+
+    *CONNECTION_SETTINGS.lock_recover() = None;
+    status::set_clients(Vec::new());
+    server::stop();
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn stop() -> i32 {
-    let mut drpc = DRPC.lock().unwrap();
+    catch_panic("stop", -1, || {
+        shutdown();
 
-    if let Some(client) = drpc.as_mut() {
-        client.close().map_err(Error::DiscordFailed).ok().is_some() as i32
-    } else {
-        -1
-    }
+        0
+    })
 }
 
 #[unsafe(no_mangle)]
 extern "C" fn start() -> i32 {
-    let api = API.get().unwrap();
+    catch_panic("start", -1, || {
+        let Some(api) = API.get() else {
+            return -1;
+        };
 
-    if let Err(e) = config_update() {
-        api.trace(format!("Failed to start Discord RPC plugin: {:?}", e));
-        -1
-    } else {
-        0
-    }
+        worker::resume();
+
+        if let Err(e) = config_update() {
+            api.log_error(format!("Failed to start Discord RPC plugin: {:?}", e));
+            status::record_error("connection", &e);
+            -1
+        } else {
+            0
+        }
+    })
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn get_actions(_: *mut DB_playItem_t) -> *mut DB_plugin_action_t {
+    ACTIONS.first()
 }
 
 /// # Safety
@@ -259,8 +428,9 @@ extern "C" fn start() -> i32 {
 /// All static strings used (PLUGIN_ID, PLUGIN_NAME, etc.) must be valid for the program's lifetime.
 #[unsafe(no_mangle)]
 pub unsafe extern "C" fn discordrpc_load(ptr: *const DB_functions_t) -> *mut DB_plugin_t {
-    unsafe {
-        API.set(&*ptr).unwrap();
+    if let Some(api) = unsafe { ptr.as_ref() } {
+        // Ignore reloads; the first function table stays valid for the process lifetime.
+        API.set(api).ok();
     }
 
     ptr::addr_of!(*PLUGIN.0)
@@ -0,0 +1,520 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use json::{JsonValue, object};
+use lazy_static::lazy_static;
+
+use crate::{
+    API,
+    config::{ConfigDefault, ConfigKey},
+    curl::{self, Body},
+    discordrpc::Presence,
+    error::{Error, Result},
+    sink::PresenceSink,
+    status,
+    util::{MutexExt, plugin_config_dir},
+    worker,
+};
+
+/// A track counts as listened once half of it, or this much of it, has played.
+const MAX_LISTEN_THRESHOLD: Duration = Duration::from_secs(4 * 60);
+/// ListenBrainz accepts at most this many listens per import.
+const MAX_IMPORT_LISTENS: usize = 100;
+const MEDIA_PLAYER: &str = "DeaDBeeF";
+const SUBMISSION_CLIENT: &str = "deadbeef-plugin-discord-rpc";
+
+/// Bumped whenever the listen or its play/pause state changes, so outdated timers give up.
+static LISTEN_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref CURRENT_LISTEN: Mutex<Option<Listen>> = Mutex::new(None);
+    /// Serializes access to the offline queue file.
+    static ref QUEUE: Mutex<()> = Mutex::new(());
+}
+
+/// The track being listened to and how much of it has played.
+struct Listen {
+    track: usize,
+    play: u64,
+    /// When the track started, as Unix time.
+    listened_at: i64,
+    metadata: JsonValue,
+    threshold: Duration,
+    /// Play time before the last pause.
+    played: Duration,
+    /// When playback last resumed, `None` while paused.
+    playing_since: Option<Instant>,
+    submitted: bool,
+}
+
+impl Listen {
+    fn played(&self) -> Duration {
+        self.played
+            + self
+                .playing_since
+                .map(|since| since.elapsed())
+                .unwrap_or_default()
+    }
+}
+
+fn queue_path() -> Result<PathBuf> {
+    Ok(plugin_config_dir()?.join("listenbrainz_queue.jsonl"))
+}
+
+fn field<'a>(presence: &'a Presence, key: &str) -> Option<&'a str> {
+    presence
+        .track_fields
+        .iter()
+        .flatten()
+        .find(|(field, _)| *field == key)
+        .map(|(_, value)| value.trim())
+        .filter(|value| !value.is_empty())
+}
+
+/// `track_metadata` for the track, `None` if it lacks the artist or title ListenBrainz requires.
+fn track_metadata(presence: &Presence) -> Option<JsonValue> {
+    let mut additional_info = object! {
+        media_player: MEDIA_PLAYER,
+        submission_client: SUBMISSION_CLIENT,
+        submission_client_version: env!("CARGO_PKG_VERSION"),
+    };
+
+    for (key, info_key) in [
+        ("recording_mbid", "recording_mbid"),
+        ("release_mbid", "release_mbid"),
+        ("track_number", "tracknumber"),
+    ] {
+        if let Some(value) = field(presence, key) {
+            additional_info[info_key] = value.into();
+        }
+    }
+    if let Some(artist_mbid) = field(presence, "artist_mbid") {
+        additional_info["artist_mbids"] = artist_mbid
+            .split(['/', ';'])
+            .map(str::trim)
+            .collect::<Vec<_>>()
+            .into();
+    }
+    if let Some(length) = track_length(presence) {
+        additional_info["duration_ms"] = (length.as_millis() as u64).into();
+    }
+
+    let mut metadata = object! {
+        artist_name: field(presence, "artist")?,
+        track_name: field(presence, "title")?,
+        additional_info: additional_info,
+    };
+
+    if let Some(album) = field(presence, "album") {
+        metadata["release_name"] = album.into();
+    }
+
+    Some(metadata)
+}
+
+fn track_length(presence: &Presence) -> Option<Duration> {
+    field(presence, "length")?
+        .parse::<f64>()
+        .ok()
+        .filter(|length| *length > 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// Announces the presence's track as playing now and submits it as a listen once enough of it
+/// has played.
+pub struct ListenBrainzSink;
+
+impl PresenceSink for ListenBrainzSink {
+    fn name(&self) -> &'static str {
+        "listenbrainz"
+    }
+
+    fn enabled(&self) -> Result<bool> {
+        let api = API.get().unwrap();
+
+        Ok(!api
+            .conf_get_str(
+                ConfigKey::LISTENBRAINZ_TOKEN,
+                ConfigDefault::LISTENBRAINZ_TOKEN,
+            )?
+            .trim()
+            .is_empty())
+    }
+
+    fn publish(&mut self, presence: &Presence) -> Result<()> {
+        let mut current = CURRENT_LISTEN.lock_recover();
+        // Paused presences carry no timestamps.
+        let playing = presence.start_timestamp.is_some();
+
+        match current.as_mut() {
+            Some(listen) if listen.track == presence.track && listen.play == presence.play => {
+                if !playing && let Some(since) = listen.playing_since.take() {
+                    listen.played += since.elapsed();
+                } else if playing && listen.playing_since.is_none() {
+                    listen.playing_since = Some(Instant::now());
+                } else {
+                    // Seeks, covers, lyrics and rotating states leave the timer running.
+                    return Ok(());
+                }
+            }
+            _ => {
+                let Some(metadata) = track_metadata(presence) else {
+                    *current = None;
+                    return Ok(());
+                };
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_err(Error::SystemTimeError)?
+                    .as_secs() as i64;
+                let threshold = track_length(presence)
+                    .map(|length| length / 2)
+                    .unwrap_or(MAX_LISTEN_THRESHOLD)
+                    .min(MAX_LISTEN_THRESHOLD);
+
+                spawn_submit(
+                    "playing_now",
+                    vec![object! { track_metadata: metadata.clone() }],
+                )?;
+                *current = Some(Listen {
+                    track: presence.track,
+                    play: presence.play,
+                    listened_at: now,
+                    metadata,
+                    threshold,
+                    played: Duration::ZERO,
+                    playing_since: playing.then(Instant::now),
+                    submitted: false,
+                });
+            }
+        }
+
+        let generation = LISTEN_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+
+        if let Some(listen) = current.as_ref()
+            && listen.playing_since.is_some()
+            && !listen.submitted
+        {
+            start_listen_timer(generation, listen.threshold.saturating_sub(listen.played()))?;
+        }
+
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        LISTEN_GENERATION.fetch_add(1, Ordering::SeqCst);
+        *CURRENT_LISTEN.lock_recover() = None;
+
+        Ok(())
+    }
+}
+
+/// POSTs `listens` to the configured server's `submit-listens` endpoint.
+fn submit(listen_type: &str, listens: Vec<JsonValue>) -> Result<()> {
+    let api = API.get().unwrap();
+    let url = api.conf_get_str(ConfigKey::LISTENBRAINZ_URL, ConfigDefault::LISTENBRAINZ_URL)?;
+    let token = api.conf_get_str(
+        ConfigKey::LISTENBRAINZ_TOKEN,
+        ConfigDefault::LISTENBRAINZ_TOKEN,
+    )?;
+    let body = object! {
+        listen_type: listen_type,
+        payload: listens,
+    };
+
+    curl::post(
+        &format!("{}/1/submit-listens", url.trim().trim_end_matches('/')),
+        &[format!("Authorization: Token {}", token.trim())],
+        Body::Json(&body.dump()),
+    )?;
+
+    Ok(())
+}
+
+/// Submits `listens`, keeping completed listens on disk if the server can't be reached and
+/// sending earlier queued ones once it can.
+fn submit_or_queue(listen_type: &str, listens: Vec<JsonValue>) -> Result<()> {
+    if let Err(e) = submit(listen_type, listens.clone()) {
+        if listen_type != "playing_now" {
+            queue_listens(&listens)?;
+        }
+        return Err(e);
+    }
+
+    flush_queue()
+}
+
+fn queue_listens(listens: &[JsonValue]) -> Result<()> {
+    let _queue = QUEUE.lock_recover();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(queue_path()?)
+        .map_err(Error::Io)?;
+
+    for listen in listens {
+        writeln!(file, "{}", listen.dump()).map_err(Error::Io)?;
+    }
+
+    Ok(())
+}
+
+fn flush_queue() -> Result<()> {
+    let api = API.get().unwrap();
+    let _queue = QUEUE.lock_recover();
+    let path = queue_path()?;
+    let Ok(content) = fs::read_to_string(&path) else {
+        return Ok(());
+    };
+    let listens = content
+        .lines()
+        .filter_map(|line| json::parse(line).ok())
+        .collect::<Vec<_>>();
+
+    for (index, chunk) in listens.chunks(MAX_IMPORT_LISTENS).enumerate() {
+        if let Err(e) = submit("import", chunk.to_vec()) {
+            // Keep what wasn't sent for the next attempt.
+            let rest = listens[index * MAX_IMPORT_LISTENS..]
+                .iter()
+                .map(|listen| format!("{}\n", listen.dump()))
+                .collect::<String>();
+
+            fs::write(&path, rest).map_err(Error::Io)?;
+            return Err(e);
+        }
+    }
+
+    api.log_info(format!(
+        "Submitted {} queued ListenBrainz listens.",
+        listens.len()
+    ));
+    fs::remove_file(&path).map_err(Error::Io)
+}
+
+struct SubmitThreadData {
+    listen_type: &'static str,
+    listens: Vec<JsonValue>,
+}
+
+/// Submits from a worker so a slow server never holds up the other sinks.
+fn spawn_submit(listen_type: &'static str, listens: Vec<JsonValue>) -> Result<()> {
+    worker::spawn_with(
+        "listenbrainz_submit_thread",
+        submit_thread,
+        SubmitThreadData {
+            listen_type,
+            listens,
+        },
+    )
+}
+
+fn submit_thread(data: SubmitThreadData) {
+    if let Err(e) = submit_or_queue(data.listen_type, data.listens) {
+        API.get().unwrap().log_warn(format!(
+            "Failed to submit {} to ListenBrainz: {:?}",
+            data.listen_type, e
+        ));
+        status::record_error("listenbrainz", &e);
+    }
+}
+
+struct ListenTimerData {
+    generation: u64,
+    delay: Duration,
+}
+
+fn start_listen_timer(generation: u64, delay: Duration) -> Result<()> {
+    worker::spawn_with(
+        "listen_timer_thread",
+        listen_timer_thread,
+        ListenTimerData { generation, delay },
+    )
+}
+
+/// Submits the current track as a listen once `delay` has passed without another presence change.
+fn listen_timer_thread(data: ListenTimerData) {
+    let is_current = || LISTEN_GENERATION.load(Ordering::SeqCst) == data.generation;
+
+    if !worker::sleep_while(data.delay, is_current) {
+        return;
+    }
+
+    let listen = {
+        let mut current = CURRENT_LISTEN.lock_recover();
+
+        match current.as_mut() {
+            Some(listen) if is_current() && !listen.submitted => {
+                listen.submitted = true;
+                object! {
+                    listened_at: listen.listened_at,
+                    track_metadata: listen.metadata.clone(),
+                }
+            }
+            _ => return,
+        }
+    };
+
+    if let Err(e) = submit_or_queue("single", vec![listen]) {
+        API.get().unwrap().log_warn(format!(
+            "Failed to submit the listen to ListenBrainz: {:?}",
+            e
+        ));
+        status::record_error("listenbrainz", &e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{Receiver, RecvTimeoutError};
+
+    use super::*;
+    use crate::deadbeef::testing::{self, presence};
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    /// Points the sink at a local server answering with `statuses` and forgets earlier listens.
+    fn setup(statuses: Vec<u16>) -> Receiver<testing::Request> {
+        let (url, requests) = testing::serve(statuses);
+
+        testing::set_conf(ConfigKey::LISTENBRAINZ_TOKEN, "secret-token");
+        testing::set_conf(ConfigKey::LISTENBRAINZ_URL, format!("{}/", url));
+        ListenBrainzSink.clear().unwrap();
+        fs::remove_file(queue_path().unwrap()).ok();
+
+        requests
+    }
+
+    /// A two second track, so it counts as listened after one second.
+    fn track(play: u64) -> Presence {
+        Presence {
+            play,
+            track_fields: Some(vec![
+                ("artist", "Artist".to_string()),
+                ("title", "Title".to_string()),
+                ("album", "Album".to_string()),
+                ("length", "2".to_string()),
+            ]),
+            ..presence(1)
+        }
+    }
+
+    fn next_body(requests: &Receiver<testing::Request>) -> JsonValue {
+        let request = requests.recv_timeout(WAIT).unwrap();
+
+        assert!(request.head.starts_with("POST /1/submit-listens HTTP/1.1"));
+        assert_eq!(request.header("Authorization"), Some("Token secret-token"));
+        json::parse(&request.body).unwrap()
+    }
+
+    fn queued() -> Vec<JsonValue> {
+        fs::read_to_string(queue_path().unwrap())
+            .unwrap_or_default()
+            .lines()
+            .map(|line| json::parse(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn announces_playing_now_then_submits_one_listen() {
+        let _api = testing::setup();
+        let requests = setup(Vec::new());
+
+        ListenBrainzSink.publish(&track(1)).unwrap();
+
+        let playing_now = next_body(&requests);
+
+        assert_eq!(playing_now["listen_type"], "playing_now");
+        assert_eq!(
+            playing_now["payload"][0]["track_metadata"]["artist_name"],
+            "Artist"
+        );
+        assert_eq!(
+            playing_now["payload"][0]["track_metadata"]["additional_info"]["duration_ms"],
+            2000
+        );
+        assert!(playing_now["payload"][0]["listened_at"].is_null());
+
+        // Later updates of the same play, e.g. the resolved cover, don't restart the timer.
+        ListenBrainzSink.publish(&track(1)).unwrap();
+
+        let listen = next_body(&requests);
+
+        assert_eq!(listen["listen_type"], "single");
+        assert_eq!(
+            listen["payload"][0]["track_metadata"]["track_name"],
+            "Title"
+        );
+        assert!(listen["payload"][0]["listened_at"].is_number());
+        assert_eq!(
+            requests.recv_timeout(Duration::from_secs(2)).unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+    }
+
+    #[test]
+    fn repeating_a_track_starts_a_new_listen() {
+        let _api = testing::setup();
+        let requests = setup(Vec::new());
+
+        ListenBrainzSink.publish(&track(1)).unwrap();
+        assert_eq!(next_body(&requests)["listen_type"], "playing_now");
+
+        ListenBrainzSink.publish(&track(2)).unwrap();
+        assert_eq!(next_body(&requests)["listen_type"], "playing_now");
+        assert_eq!(next_body(&requests)["listen_type"], "single");
+    }
+
+    #[test]
+    fn queues_listens_that_fail_to_submit() {
+        let _api = testing::setup();
+        let requests = setup(vec![200, 500]);
+
+        ListenBrainzSink.publish(&track(1)).unwrap();
+        assert_eq!(next_body(&requests)["listen_type"], "playing_now");
+        assert_eq!(next_body(&requests)["listen_type"], "single");
+
+        // Written right after the failed response arrives.
+        let mut queue = Vec::new();
+
+        for _ in 0..50 {
+            queue = queued();
+
+            if !queue.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0]["track_metadata"]["track_name"], "Title");
+    }
+
+    #[test]
+    fn flushing_keeps_only_the_unsent_tail() {
+        let _api = testing::setup();
+        let requests = setup(vec![200, 200, 500]);
+        let listens = (0..150)
+            .map(|listened_at| object! { listened_at: listened_at, track_metadata: {} })
+            .collect::<Vec<_>>();
+
+        queue_listens(&listens).unwrap();
+
+        assert!(submit_or_queue("single", vec![object! { listened_at: 1000 }]).is_err());
+        assert_eq!(next_body(&requests)["listen_type"], "single");
+
+        let first_import = next_body(&requests);
+        let second_import = next_body(&requests);
+
+        assert_eq!(first_import["listen_type"], "import");
+        assert_eq!(first_import["payload"].len(), MAX_IMPORT_LISTENS);
+        assert_eq!(second_import["payload"].len(), 50);
+        assert_eq!(queued(), listens[MAX_IMPORT_LISTENS..]);
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+
+use crate::{
+    API,
+    deadbeef::safe_wrapper::{SafeDBPlayItem, SafeDBPlayList},
+    discordrpc::{MIN_UPDATE_INTERVAL, patch_state},
+    error::Result,
+    util::{format_string, item_meta, nowplaying_position},
+    worker,
+};
+
+/// Shown between lines that are merged because they come faster than Discord can update.
+const LINE_SEPARATOR: &str = " / ";
+
+/// Bumped on every presence update so the previous track's lyrics stop.
+static LYRICS_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// One line of synced lyrics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LyricLine {
+    /// Seconds into the track.
+    pub time: f32,
+    pub text: String,
+}
+
+/// Reads an `[mm:ss.xx]` timestamp.
+fn parse_timestamp(tag: &str) -> Option<f32> {
+    let (minutes, seconds) = tag.split_once(':')?;
+    // Some files separate the hundredths with a second colon.
+    let seconds = seconds.replacen(':', ".", 1);
+    let minutes = minutes.trim().parse::<u32>().ok()?;
+    let seconds = seconds.trim().parse::<f32>().ok()?;
+
+    Some(minutes as f32 * 60.0 + seconds)
+}
+
+/// Parses LRC lyrics, including lines with several timestamps and the `[offset:]` tag.
+///
+/// Metadata tags and lines without a timestamp are skipped; enhanced LRC word timestamps are
+/// removed from the text.
+pub fn parse_lrc(content: &str) -> Vec<LyricLine> {
+    let word_timestamps = Regex::new(r"<\d+:\d+([.:]\d+)?>").unwrap();
+    let mut offset = 0.0;
+    let mut lines = Vec::new();
+
+    for line in content.trim_start_matches('\u{feff}').lines() {
+        let mut rest = line.trim();
+        let mut times = Vec::new();
+
+        while let Some((tag, after)) = rest.strip_prefix('[').and_then(|rest| rest.split_once(']'))
+        {
+            if let Some(time) = parse_timestamp(tag) {
+                times.push(time);
+            } else if let Some(value) = tag.strip_prefix("offset:") {
+                // Milliseconds; a positive offset shows the lyrics sooner.
+                offset = value.trim().parse::<f32>().unwrap_or(0.0) / 1000.0;
+            }
+            rest = after;
+        }
+
+        let text = word_timestamps.replace_all(rest, "").trim().to_string();
+
+        lines.extend(times.into_iter().map(|time| LyricLine {
+            time,
+            text: text.clone(),
+        }));
+    }
+
+    for line in &mut lines {
+        line.time = (line.time - offset).max(0.0);
+    }
+    lines.sort_by(|a, b| a.time.total_cmp(&b.time));
+
+    lines
+}
+
+/// Synced lyrics of `item` from a `.lrc` file next to it, or its `SYNCEDLYRICS` or `LYRICS` tag.
+pub fn load_lyrics(item: &SafeDBPlayItem, plt: &SafeDBPlayList) -> Result<Option<Vec<LyricLine>>> {
+    let path = PathBuf::from(format_string(item, plt, "%path%")?).with_extension("lrc");
+    let sources = [
+        fs::read(&path)
+            .ok()
+            .map(|content| String::from_utf8_lossy(&content).to_string()),
+        item_meta(item, c"SYNCEDLYRICS")?,
+        item_meta(item, c"LYRICS")?,
+    ];
+
+    // Plain lyrics without timestamps parse to nothing and fall through to the next source.
+    Ok(sources
+        .into_iter()
+        .flatten()
+        .map(|content| parse_lrc(&content))
+        .find(|lines| !lines.is_empty()))
+}
+
+/// The text to show at `position` and when it changes next.
+///
+/// Lines starting before another update is allowed are merged into the current one.
+pub fn lyrics_at(lines: &[LyricLine], position: f32) -> (Option<String>, Option<f32>) {
+    let Some(first) = lines.iter().rposition(|line| line.time <= position) else {
+        return (None, lines.first().map(|line| line.time));
+    };
+    let merge_until = position + MIN_UPDATE_INTERVAL.as_secs_f32();
+    let end = lines[first + 1..]
+        .iter()
+        .position(|line| line.time >= merge_until)
+        .map_or(lines.len(), |index| first + 1 + index);
+    let text = lines[first..end]
+        .iter()
+        .map(|line| line.text.as_str())
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join(LINE_SEPARATOR);
+
+    (
+        (!text.is_empty()).then_some(text),
+        lines.get(end).map(|line| line.time),
+    )
+}
+
+/// Stops showing the lyrics of the previous presence and returns the generation for the next.
+pub fn stop_lyrics() -> u64 {
+    LYRICS_GENERATION.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+struct LyricsThreadData {
+    generation: u64,
+    track: usize,
+    lines: Vec<LyricLine>,
+    /// State shown before the first line and during instrumental breaks.
+    fallback_state: String,
+    /// Lyrics already shown by the presence update that started the thread.
+    shown: Option<String>,
+}
+
+/// Follows the playback position of `track`, showing the current lyric line as the state, until
+/// the lyrics are stopped again after `generation`.
+pub fn start_lyrics(
+    generation: u64,
+    track: usize,
+    lines: Vec<LyricLine>,
+    fallback_state: String,
+    shown: Option<String>,
+) -> Result<()> {
+    let data = LyricsThreadData {
+        generation,
+        track,
+        lines,
+        fallback_state,
+        shown,
+    };
+
+    worker::spawn_with("lyrics_thread", lyrics_thread, data)
+}
+
+fn lyrics_thread(data: LyricsThreadData) {
+    let api = API.get().unwrap();
+    let is_current = || LYRICS_GENERATION.load(Ordering::SeqCst) == data.generation;
+    // The presence update that started the lyrics counts against the rate limit too.
+    let mut last_update = Instant::now();
+    let mut shown = data
+        .shown
+        .clone()
+        .unwrap_or_else(|| data.fallback_state.clone());
+    let mut wake_at = last_update + MIN_UPDATE_INTERVAL;
+
+    loop {
+        if !worker::sleep_while(
+            wake_at.saturating_duration_since(Instant::now()),
+            is_current,
+        ) {
+            return;
+        }
+
+        let position = match nowplaying_position() {
+            Ok(position) => position,
+            Err(e) => {
+                api.log_debug(format!("Stopping lyrics: {:?}", e));
+                return;
+            }
+        };
+        let (text, next_change) = lyrics_at(&data.lines, position);
+        let text = text.unwrap_or_else(|| data.fallback_state.clone());
+
+        if !is_current() {
+            return;
+        }
+        if text != shown {
+            if let Err(e) = patch_state(data.track, text.clone(), is_current) {
+                api.log_debug(format!("Failed to show lyrics: {:?}", e));
+            }
+            shown = text;
+            last_update = Instant::now();
+        }
+
+        let Some(next_change) = next_change else {
+            return;
+        };
+        let until_next = Duration::from_secs_f32((next_change - position).max(0.0));
+
+        wake_at = (Instant::now() + until_next).max(last_update + MIN_UPDATE_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(time: f32, text: &str) -> LyricLine {
+        LyricLine {
+            time,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn lines_with_several_timestamps_are_repeated_in_order() {
+        assert_eq!(
+            parse_lrc("[00:10.00][00:30.00]Chorus\n[00:20.50]Verse"),
+            vec![
+                line(10.0, "Chorus"),
+                line(20.5, "Verse"),
+                line(30.0, "Chorus")
+            ]
+        );
+    }
+
+    #[test]
+    fn metadata_and_untimed_lines_are_skipped() {
+        assert_eq!(
+            parse_lrc("[ar:Artist]\n[ti:Title]\nNo timestamp\n\n[01:02.5]Line"),
+            vec![line(62.5, "Line")]
+        );
+    }
+
+    #[test]
+    fn offset_shifts_every_line() {
+        assert_eq!(
+            parse_lrc("[00:01.00]First\n[offset:+1500]\n[00:10.00]Second"),
+            vec![line(0.0, "First"), line(8.5, "Second")]
+        );
+        assert_eq!(
+            parse_lrc("[offset:-500]\n[00:10.00]Later"),
+            vec![line(10.5, "Later")]
+        );
+    }
+
+    #[test]
+    fn hundredths_may_follow_a_colon() {
+        assert_eq!(parse_lrc("[00:12:34]Colon"), vec![line(12.34, "Colon")]);
+    }
+
+    #[test]
+    fn word_timestamps_are_removed() {
+        assert_eq!(
+            parse_lrc("[00:05.00]<00:05.00>Every <00:05.50>word <00:06:00>timed"),
+            vec![line(5.0, "Every word timed")]
+        );
+    }
+
+    #[test]
+    fn byte_order_mark_is_ignored() {
+        assert_eq!(
+            parse_lrc("\u{feff}[00:01.00]First"),
+            vec![line(1.0, "First")]
+        );
+    }
+
+    #[test]
+    fn nothing_is_shown_before_the_first_line() {
+        let lines = vec![line(5.0, "First"), line(20.0, "Second")];
+
+        assert_eq!(lyrics_at(&lines, 1.0), (None, Some(5.0)));
+        assert_eq!(
+            lyrics_at(&lines, 6.0),
+            (Some("First".to_string()), Some(20.0))
+        );
+        assert_eq!(lyrics_at(&lines, 25.0), (Some("Second".to_string()), None));
+    }
+
+    #[test]
+    fn lines_within_the_update_interval_are_merged() {
+        let lines = vec![
+            line(10.0, "One"),
+            line(11.0, "Two"),
+            line(12.0, ""),
+            line(13.9, "Three"),
+            line(14.0, "Four"),
+        ];
+
+        assert_eq!(
+            lyrics_at(&lines, 10.0),
+            (Some("One / Two / Three".to_string()), Some(14.0))
+        );
+    }
+
+    #[test]
+    fn empty_lines_are_instrumental_breaks() {
+        let lines = vec![line(10.0, "Sung"), line(20.0, ""), line(40.0, "Again")];
+
+        assert_eq!(lyrics_at(&lines, 25.0), (None, Some(40.0)));
+    }
+}
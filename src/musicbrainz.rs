@@ -1,40 +1,125 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
 use json::JsonValue;
+use lazy_static::lazy_static;
 use urlencoding::encode;
 
 use crate::{
     API,
+    config::CoverSize,
     error::{Error, Result},
+    romanize::is_latin,
+    util::MutexExt,
+    worker,
 };
 
+/// MusicBrainz allows one request per second per client.
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_CACHED_RELEASES: usize = 512;
+
+lazy_static! {
+    static ref LAST_REQUEST: Mutex<Option<Instant>> = Mutex::new(None);
+    /// Latin-script names of the release each album query resolved to.
+    static ref LATIN_NAMES: Mutex<HashMap<String, Vec<(String, String)>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Fetches `url` from the MusicBrainz API, spacing requests out to stay within its rate limit.
+fn musicbrainz_get(url: &str) -> Result<String> {
+    let api = API.get().unwrap();
+
+    // Reserve the next slot, then wait for it without holding the lock.
+    let wait = {
+        let mut last_request = LAST_REQUEST.lock_recover();
+        let now = Instant::now();
+        let slot = last_request.map_or(now, |last| (last + REQUEST_INTERVAL).max(now));
+
+        *last_request = Some(slot);
+        slot - now
+    };
+
+    if !worker::sleep_while(wait, || true) {
+        return Err(Error::Cancelled);
+    }
+
+    api.http_get(url)
+}
+
 fn query_album_json(query: &str) -> Result<String> {
     let url = format!(
         "https://musicbrainz.org/ws/2/release?query={}&fmt=json&limit=10",
         encode(query)
     );
-    let api = API.get().unwrap();
 
-    api.http_get(&url)
+    musicbrainz_get(&url)
 }
 
-fn release_has_artwork(mb_release_id: &str) -> Result<bool> {
+fn query_release(mb_release_id: &str) -> Result<JsonValue> {
     let url = format!(
-        "https://musicbrainz.org/ws/2/release/{}?fmt=json",
+        "https://musicbrainz.org/ws/2/release/{}?inc=artist-credits+release-rels&fmt=json",
         mb_release_id
     );
-    let api = API.get().unwrap();
-    let json_raw = api.http_get(&url)?;
-    let json = json::parse(&json_raw).map_err(Error::JsonParseFailed)?;
+    let json_raw = musicbrainz_get(&url)?;
 
-    if let JsonValue::Object(object) = json
+    json::parse(&json_raw).map_err(Error::JsonParseFailed)
+}
+
+fn release_has_artwork(release: &JsonValue) -> bool {
+    if let JsonValue::Object(object) = release
         && let Some(cover_art_archive) = object.get("cover-art-archive")
         && let JsonValue::Object(cover_art_archive) = cover_art_archive
         && let Some(artwork) = cover_art_archive.get("artwork")
         && let JsonValue::Boolean(has_artwork) = artwork
     {
-        Ok(*has_artwork)
+        *has_artwork
     } else {
-        Ok(false)
+        false
+    }
+}
+
+/// Latin-script names for a release: the title of its transliterated pseudo-release and the
+/// sort names of its credited artists, as `(original, latin)` pairs.
+fn release_latin_names(release: &JsonValue) -> Vec<(String, String)> {
+    let mut names = Vec::new();
+
+    if let Some(title) = release["title"].as_str()
+        && let Some(latin_title) = release["relations"]
+            .members()
+            .filter(|relation| relation["type"] == "transl-tracklisting")
+            .map(|relation| &relation["release"])
+            .filter(|pseudo| pseudo["text-representation"]["script"] == "Latn")
+            .find_map(|pseudo| pseudo["title"].as_str())
+        && latin_title != title
+    {
+        names.push((title.to_string(), latin_title.to_string()));
     }
+
+    for credit in release["artist-credit"].members() {
+        if let Some(name) = credit["name"].as_str()
+            && let Some(sort_name) = credit["artist"]["sort-name"].as_str()
+            && !is_latin(name)
+            && is_latin(sort_name)
+        {
+            // Sort names read "Family, Given"; show them in the usual order.
+            let latin_name = match sort_name.split_once(", ") {
+                Some((family, given)) => format!("{} {}", given, family),
+                None => sort_name.to_string(),
+            };
+
+            names.push((name.to_string(), latin_name));
+        }
+    }
+
+    names
+}
+
+/// Latin-script names found for `query` when its release was resolved, if it has been.
+pub fn latin_names_for_query(query: &str) -> Option<Vec<(String, String)>> {
+    LATIN_NAMES.lock_recover().get(query).cloned()
 }
 
 fn query_releases(query: &str) -> Result<Vec<String>> {
@@ -63,20 +148,46 @@ fn query_releases(query: &str) -> Result<Vec<String>> {
     }
 }
 
-pub fn get_album_cover_url_from_query(query: &str) -> Result<String> {
+/// Finds the first release for `query` that has artwork and remembers its Latin names.
+fn find_release(query: &str) -> Result<String> {
     let mb_release_ids = query_releases(query)?;
     for mb_release_id in mb_release_ids {
-        if release_has_artwork(&mb_release_id)? {
-            return Ok(get_album_cover_url(&mb_release_id));
+        let release = query_release(&mb_release_id)?;
+
+        if release_has_artwork(&release) {
+            let mut latin_names = LATIN_NAMES.lock_recover();
+
+            if latin_names.len() >= MAX_CACHED_RELEASES {
+                latin_names.clear();
+            }
+            latin_names.insert(query.to_string(), release_latin_names(&release));
+
+            return Ok(mb_release_id);
         }
     }
 
     Err(Error::MusicbrainzNoReleaseFound)
 }
 
-fn get_album_cover_url(mb_release_id: &str) -> String {
+pub fn get_album_cover_url_from_query(query: &str, size: CoverSize) -> Result<String> {
+    Ok(get_album_cover_url(&find_release(query)?, size))
+}
+
+/// Latin-script names for `query`, resolving its release when no cover lookup did yet.
+pub fn resolve_latin_names(query: &str) -> Result<Vec<(String, String)>> {
+    if let Some(names) = latin_names_for_query(query) {
+        return Ok(names);
+    }
+
+    find_release(query)?;
+
+    Ok(latin_names_for_query(query).unwrap_or_default())
+}
+
+fn get_album_cover_url(mb_release_id: &str, size: CoverSize) -> String {
     format!(
-        "https://coverartarchive.org/release/{}/front-250",
-        mb_release_id
+        "https://coverartarchive.org/release/{}/{}",
+        mb_release_id,
+        size.caa_suffix()
     )
 }
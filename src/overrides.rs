@@ -0,0 +1,183 @@
+use std::{ffi::CStr, fs, path::PathBuf};
+
+use crate::{
+    API,
+    deadbeef::{
+        PL_MAIN,
+        safe_wrapper::{SafeDBPlayItem, SafeDBPlayList},
+    },
+    error::{Error, Result},
+    util::{format_string, item_meta, open_with_default_app, plugin_config_dir},
+};
+
+const ARTIST_SCRIPT: &str = "%album artist%";
+const ALBUM_SCRIPT: &str = "%album%";
+const MBID_SCRIPT: &str = "$if2(%musicbrainz_albumid%,)";
+
+const OVERRIDES_HEADER: &str = "\
+# Cover overrides for the Discord Rich Presence plugin.
+# One entry per line, checked before any cover provider:
+#   <album artist> | <album> = <cover URL or Discord asset key>
+#   mbid:<MusicBrainz release ID> = <cover URL or Discord asset key>
+# Matching ignores case and surrounding spaces. Lines starting with # are ignored.
+";
+
+/// Presence overrides read from the playing track's own `DISCORD_*` tags.
+#[derive(Debug, Default)]
+pub struct TagOverrides {
+    /// `DISCORD_HIDE=1`: show nothing for this track.
+    pub hide: bool,
+    /// `DISCORD_COVER`: cover URL or Discord asset key.
+    pub cover: Option<String>,
+    /// `DISCORD_DETAILS`: replaces the title format script.
+    pub details_script: Option<String>,
+    /// `DISCORD_STATE`: replaces the state format script.
+    pub state_script: Option<String>,
+    /// `DISCORD_ICON_TEXT`: replaces the icon text format script.
+    pub icon_text_script: Option<String>,
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn tag(item: &SafeDBPlayItem, key: &CStr) -> Result<Option<String>> {
+    Ok(non_empty(item_meta(item, key)?))
+}
+
+pub fn nowplaying_tag_overrides() -> Result<TagOverrides> {
+    tag_overrides(&API.get().unwrap().streamer_get_playing_track()?)
+}
+
+pub fn tag_overrides(item: &SafeDBPlayItem) -> Result<TagOverrides> {
+    let hide = tag(item, c"DISCORD_HIDE")?
+        .is_some_and(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes"));
+
+    Ok(TagOverrides {
+        hide,
+        cover: tag(item, c"DISCORD_COVER")?,
+        details_script: tag(item, c"DISCORD_DETAILS")?,
+        state_script: tag(item, c"DISCORD_STATE")?,
+        icon_text_script: tag(item, c"DISCORD_ICON_TEXT")?,
+    })
+}
+
+struct AlbumKey {
+    artist: String,
+    album: String,
+    mbid: String,
+}
+
+impl AlbumKey {
+    fn line_key(&self) -> String {
+        format!("{} | {}", self.artist, self.album)
+    }
+}
+
+fn overrides_path() -> Result<PathBuf> {
+    Ok(plugin_config_dir()?.join("cover_overrides.txt"))
+}
+
+/// Parses `key = value` lines, skipping comments and malformed entries.
+fn parse_overrides(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.rsplit_once(" = "))
+        .map(|(key, value)| (normalize(key), value.trim().to_string()))
+        .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+        .collect()
+}
+
+fn normalize(key: &str) -> String {
+    key.split('|')
+        .map(|part| part.trim().to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+fn find_override(overrides: &[(String, String)], key: &AlbumKey) -> Option<String> {
+    let mbid_key = normalize(&format!("mbid:{}", key.mbid));
+    let album_key = normalize(&key.line_key());
+
+    // A release ID is more specific than the album name, so it wins.
+    overrides
+        .iter()
+        .find(|(entry, _)| !key.mbid.is_empty() && *entry == mbid_key)
+        .or_else(|| overrides.iter().find(|(entry, _)| *entry == album_key))
+        .map(|(_, value)| value.clone())
+}
+
+/// Cover URL or asset key configured for the playing album, if any.
+pub fn nowplaying_cover_override() -> Result<Option<String>> {
+    let api = API.get().unwrap();
+
+    cover_override(&api.streamer_get_playing_track()?, &api.plt_get_curr()?)
+}
+
+/// Cover URL or asset key configured for `item`'s album, if any.
+pub fn cover_override(item: &SafeDBPlayItem, plt: &SafeDBPlayList) -> Result<Option<String>> {
+    let content = match fs::read_to_string(overrides_path()?) {
+        Ok(content) => content,
+        Err(_) => return Ok(None),
+    };
+    let key = AlbumKey {
+        artist: format_string(item, plt, ARTIST_SCRIPT)?,
+        album: format_string(item, plt, ALBUM_SCRIPT)?,
+        mbid: format_string(item, plt, MBID_SCRIPT)?,
+    };
+
+    Ok(find_override(&parse_overrides(&content), &key))
+}
+
+fn first_selected_item(plt: &SafeDBPlayList) -> Result<Option<SafeDBPlayItem>> {
+    let api = API.get().unwrap();
+    let mut item = api.plt_get_first(plt, PL_MAIN)?;
+
+    while !item.is_null() {
+        if api.pl_is_selected(&item)? {
+            return Ok(Some(item));
+        }
+        item = api.pl_get_next(&item, PL_MAIN)?;
+    }
+
+    Ok(None)
+}
+
+/// Adds a template entry for the selected track's album (if missing) and opens the overrides file.
+pub fn edit_selected_override() -> Result<()> {
+    let api = API.get().unwrap();
+    let path = overrides_path()?;
+    let mut content = fs::read_to_string(&path).unwrap_or_else(|_| OVERRIDES_HEADER.to_string());
+    let plt = api.action_get_playlist()?;
+
+    if let Some(item) = first_selected_item(&plt)? {
+        let key = AlbumKey {
+            artist: format_string(&item, &plt, ARTIST_SCRIPT)?,
+            album: format_string(&item, &plt, ALBUM_SCRIPT)?,
+            mbid: format_string(&item, &plt, MBID_SCRIPT)?,
+        };
+
+        // Added commented out so the current cover keeps showing until the user fills it in.
+        let template = format!("# {} = ", key.line_key());
+
+        if find_override(&parse_overrides(&content), &key).is_none() && !content.contains(&template)
+        {
+            if !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.push_str(&template);
+            content.push('\n');
+            fs::write(&path, &content).map_err(Error::Io)?;
+        }
+    } else if !path.exists() {
+        fs::write(&path, &content).map_err(Error::Io)?;
+    }
+
+    api.log_info(format!("Opening cover overrides file {}", path.display()));
+
+    open_with_default_app(&path)
+}
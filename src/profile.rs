@@ -0,0 +1,303 @@
+use std::{fs, path::PathBuf, sync::Mutex, time::SystemTime};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    API,
+    config::{ActivityKind, ConfigDefault, ConfigKey, CoverSource, StatusDisplay},
+    deadbeef::safe_wrapper::{SafeDBPlayItem, SafeDBPlayList},
+    error::Result,
+    util::{MutexExt, format_string, plugin_config_dir},
+};
+
+const PATH_SCRIPT: &str = "%path%";
+const GENRE_SCRIPT: &str = "%genre%";
+/// Discord shows at most two buttons.
+const MAX_BUTTONS: usize = 2;
+
+lazy_static! {
+    /// `profiles.txt` as last parsed, with its modification time; only re-read once it changes.
+    static ref PROFILE_FILE: Mutex<Option<(SystemTime, Vec<Section>)>> = Mutex::new(None);
+}
+
+/// What decides whether a profile applies to a track.
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    /// `script:` a title formatting expression that evaluates to something other than empty or 0.
+    Script(String),
+    /// `path:` the file path starts with this prefix.
+    PathPrefix(String),
+    /// `playlist:` the track's playlist has this name.
+    Playlist(String),
+    /// `genre:` one of the track's genres is this one.
+    Genre(String),
+}
+
+impl Condition {
+    fn parse(value: &str) -> Option<Self> {
+        let (kind, argument) = value.split_once(':')?;
+        let argument = argument.trim().to_string();
+
+        if argument.is_empty() {
+            return None;
+        }
+
+        match kind.trim().to_lowercase().as_str() {
+            "script" => Some(Condition::Script(argument)),
+            "path" => Some(Condition::PathPrefix(argument)),
+            "playlist" => Some(Condition::Playlist(argument)),
+            "genre" => Some(Condition::Genre(argument)),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, item: &SafeDBPlayItem, plt: &SafeDBPlayList) -> Result<bool> {
+        let api = API.get().unwrap();
+
+        Ok(match self {
+            Condition::Script(script) => {
+                let value = format_string(item, plt, script)?;
+                let value = value.trim();
+
+                !value.is_empty() && value != "0"
+            }
+            Condition::PathPrefix(prefix) => {
+                format_string(item, plt, PATH_SCRIPT)?.starts_with(prefix)
+            }
+            Condition::Playlist(name) => {
+                let item_plt = api.pl_get_playlist(item)?;
+                let title = if item_plt.is_null() {
+                    api.plt_get_title(plt)?
+                } else {
+                    api.plt_get_title(&item_plt)?
+                };
+
+                title.trim().eq_ignore_ascii_case(name)
+            }
+            Condition::Genre(genre) => format_string(item, plt, GENRE_SCRIPT)?
+                .split([',', ';', '/'])
+                .any(|value| value.trim().eq_ignore_ascii_case(genre)),
+        })
+    }
+}
+
+/// A named set of presence templates; the settings dialog forms the default profile.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub title_script: String,
+    pub state_script: String,
+    pub icon_script: String,
+    /// Label and URL scripts of up to two buttons.
+    pub buttons: Vec<(String, String)>,
+    /// Whether the end time is shown as well as the elapsed time.
+    pub full_timestamps: bool,
+    pub cover_source: CoverSource,
+    pub activity_kind: ActivityKind,
+    pub status_display: StatusDisplay,
+    conditions: Vec<Condition>,
+}
+
+impl Profile {
+    /// The settings dialog's profile, used when no profile from `profiles.txt` matches.
+    pub fn from_config() -> Result<Self> {
+        let api = API.get().unwrap();
+
+        Ok(Self {
+            name: "default".to_string(),
+            title_script: api.conf_get_str(ConfigKey::TITLE_SCRIPT, ConfigDefault::TITLE_SCRIPT)?,
+            state_script: api.conf_get_str(ConfigKey::STATE_SCRIPT, ConfigDefault::STATE_SCRIPT)?,
+            icon_script: api.conf_get_str(ConfigKey::ICON_SCRIPT, ConfigDefault::ICON_SCRIPT)?,
+            buttons: Vec::new(),
+            full_timestamps: api
+                .conf_get_int(ConfigKey::END_TIMESTAMP2, ConfigDefault::END_TIMESTAMP2)?
+                == 1,
+            cover_source: CoverSource::try_from(
+                api.conf_get_int(ConfigKey::COVER_SOURCE, ConfigDefault::COVER_SOURCE)?,
+            )?,
+            activity_kind: ActivityKind::try_from(
+                api.conf_get_int(ConfigKey::ACTIVITY_TYPE, ConfigDefault::ACTIVITY_TYPE)?,
+            )?,
+            status_display: StatusDisplay::try_from(
+                api.conf_get_int(ConfigKey::STATUS_DISPLAY, ConfigDefault::STATUS_DISPLAY)?,
+            )?,
+            conditions: Vec::new(),
+        })
+    }
+
+    /// Applies one `key = value` line, returning false if it isn't understood.
+    fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "when" => match Condition::parse(value) {
+                Some(condition) => self.conditions.push(condition),
+                None => return false,
+            },
+            "title" => self.title_script = value.to_string(),
+            "state" => self.state_script = value.to_string(),
+            "icon" => self.icon_script = value.to_string(),
+            "button" => match value.split_once(" | ") {
+                Some((label, url)) if self.buttons.len() < MAX_BUTTONS => self
+                    .buttons
+                    .push((label.trim().to_string(), url.trim().to_string())),
+                _ => return false,
+            },
+            "timestamps" => match value.to_lowercase().as_str() {
+                "elapsed" => self.full_timestamps = false,
+                "full" => self.full_timestamps = true,
+                _ => return false,
+            },
+            "cover" => match parse_cover_chain(value) {
+                Some(cover_source) => self.cover_source = cover_source,
+                None => return false,
+            },
+            "activity" => match value.to_lowercase().as_str() {
+                "listening" => self.activity_kind = ActivityKind::Listening,
+                "playing" => self.activity_kind = ActivityKind::Playing,
+                "watching" => self.activity_kind = ActivityKind::Watching,
+                "competing" => self.activity_kind = ActivityKind::Competing,
+                _ => return false,
+            },
+            "status" => match value.to_lowercase().as_str() {
+                "name" => self.status_display = StatusDisplay::Name,
+                "state" => self.status_display = StatusDisplay::State,
+                "details" => self.status_display = StatusDisplay::Details,
+                _ => return false,
+            },
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn matches(&self, item: &SafeDBPlayItem, plt: &SafeDBPlayList) -> Result<bool> {
+        for condition in &self.conditions {
+            if condition.matches(item, plt)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+fn parse_cover_chain(value: &str) -> Option<CoverSource> {
+    let chain = value
+        .split(',')
+        .map(|source| source.trim().to_lowercase())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    match chain.as_str() {
+        "none" => Some(CoverSource::NoCover),
+        "musicbrainz" => Some(CoverSource::MusicBrainz),
+        "local" => Some(CoverSource::Local),
+        "local,musicbrainz" => Some(CoverSource::LocalThenMusicBrainz),
+        _ => None,
+    }
+}
+
+fn profiles_path() -> Result<PathBuf> {
+    Ok(plugin_config_dir()?.join("profiles.txt"))
+}
+
+/// A `[name]` section of `profiles.txt`: the `key = value` lines it sets, in order.
+struct Section {
+    name: String,
+    lines: Vec<(String, String)>,
+}
+
+impl Section {
+    /// The profile with this section's lines applied over the `default` values.
+    fn profile(&self, default: &Profile) -> Profile {
+        let mut profile = Profile {
+            name: self.name.clone(),
+            ..default.clone()
+        };
+
+        for (key, value) in &self.lines {
+            profile.set(key, value);
+        }
+
+        profile
+    }
+}
+
+/// Parses `[name]` sections of `key = value` lines, warning about and dropping the lines a
+/// profile doesn't understand.
+fn parse_sections(content: &str, default: &Profile) -> Vec<Section> {
+    let api = API.get().unwrap();
+    let mut sections: Vec<(Section, Profile)> = Vec::new();
+
+    for line in content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    {
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            let section = Section {
+                name: name.trim().to_string(),
+                lines: Vec::new(),
+            };
+            let profile = section.profile(default);
+
+            sections.push((section, profile));
+        } else if let Some((section, profile)) = sections.last_mut()
+            && let Some((key, value)) = line.split_once('=')
+            && profile.set(&key.trim().to_lowercase(), value.trim())
+        {
+            section
+                .lines
+                .push((key.trim().to_lowercase(), value.trim().to_string()));
+        } else {
+            api.log_warn(format!("Ignoring profile line '{}'.", line));
+        }
+    }
+
+    sections.into_iter().map(|(section, _)| section).collect()
+}
+
+/// The first profile from `profiles.txt` with a condition matching `item`, or the settings
+/// dialog's profile if none does.
+pub fn select_profile(item: &SafeDBPlayItem, plt: &SafeDBPlayList) -> Result<Profile> {
+    let api = API.get().unwrap();
+    let default = Profile::from_config()?;
+
+    for profile in load_profiles(&default)? {
+        if profile.matches(item, plt)? {
+            api.log_debug(format!("Using presence profile '{}'.", profile.name));
+            return Ok(profile);
+        }
+    }
+
+    Ok(default)
+}
+
+/// The profiles from `profiles.txt`, parsed again only once the file changed.
+fn load_profiles(default: &Profile) -> Result<Vec<Profile>> {
+    let path = profiles_path()?;
+    let mut profile_file = PROFILE_FILE.lock_recover();
+    let Ok(modified) = fs::metadata(&path).and_then(|metadata| metadata.modified()) else {
+        *profile_file = None;
+        return Ok(Vec::new());
+    };
+
+    if profile_file
+        .as_ref()
+        .is_none_or(|(parsed_at, _)| *parsed_at != modified)
+    {
+        let Ok(content) = fs::read_to_string(&path) else {
+            return Ok(Vec::new());
+        };
+
+        *profile_file = Some((modified, parse_sections(&content, default)));
+    }
+
+    Ok(profile_file
+        .iter()
+        .flat_map(|(_, sections)| sections)
+        .map(|section| section.profile(default))
+        .collect())
+}
@@ -0,0 +1,202 @@
+use crate::{
+    API,
+    config::{ConfigDefault, ConfigKey, Romanization, RomanizationSource},
+    error::Result,
+    musicbrainz::{latin_names_for_query, resolve_latin_names},
+};
+
+fn is_latin_char(c: char) -> bool {
+    !c.is_alphabetic() || c < '\u{0250}' || ('\u{1E00}'..='\u{1EFF}').contains(&c)
+}
+
+/// Whether `text` only uses Latin letters (accents included), so it needs no romanization.
+pub fn is_latin(text: &str) -> bool {
+    text.chars().all(is_latin_char)
+}
+
+fn is_han(c: char) -> bool {
+    matches!(c, '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}')
+}
+
+fn is_kana(c: char) -> bool {
+    matches!(c, '\u{3040}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' | '\u{FF66}'..='\u{FF9F}')
+}
+
+/// Whether `text` mixes kanji and kana, so it is Japanese for sure. Transliteration reads Han
+/// characters as Mandarin, which would turn such text into nonsense.
+fn is_japanese(text: &str) -> bool {
+    text.chars().any(is_han) && text.chars().any(is_kana)
+}
+
+/// Transliterates the non-Latin letters of `text`, keeping Latin ones (and their accents) as is.
+fn transliterate(text: &str) -> String {
+    let mut out = String::new();
+
+    for c in text.chars() {
+        if is_latin_char(c) {
+            out.push(c);
+        } else if let Some(latin) = deunicode::deunicode_char(c) {
+            // CJK syllables come with a trailing space, so separate them from what precedes too.
+            if latin.ends_with(' ') && !out.is_empty() && !out.ends_with(' ') {
+                out.push(' ');
+            }
+            out.push_str(latin);
+        }
+    }
+
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Romanizes the details, state and icon text as configured for each of them.
+pub struct Romanizer {
+    details: Romanization,
+    state: Romanization,
+    icon_text: Romanization,
+    /// `(original, latin)` names from MusicBrainz, replaced before transliterating the rest.
+    latin_names: Vec<(String, String)>,
+    /// Album query whose release hasn't been resolved yet, when names come from MusicBrainz.
+    pending_query: Option<String>,
+}
+
+impl Romanizer {
+    /// `album_query` is the cleaned MusicBrainz query of the playing album, if it has one.
+    pub fn load(album_query: Option<&str>) -> Result<Self> {
+        let api = API.get().unwrap();
+        let source = RomanizationSource::try_from(api.conf_get_int(
+            ConfigKey::ROMANIZATION_SOURCE,
+            ConfigDefault::ROMANIZATION_SOURCE,
+        )?)?;
+        let (latin_names, pending_query) = match (source, album_query) {
+            (RomanizationSource::MusicBrainz, Some(query)) => match latin_names_for_query(query) {
+                Some(names) => (names, None),
+                None => (Vec::new(), Some(query.to_string())),
+            },
+            _ => (Vec::new(), None),
+        };
+
+        Ok(Self {
+            details: Romanization::try_from(
+                api.conf_get_int(ConfigKey::ROMANIZE_DETAILS, ConfigDefault::ROMANIZE_DETAILS)?,
+            )?,
+            state: Romanization::try_from(
+                api.conf_get_int(ConfigKey::ROMANIZE_STATE, ConfigDefault::ROMANIZE_STATE)?,
+            )?,
+            icon_text: Romanization::try_from(api.conf_get_int(
+                ConfigKey::ROMANIZE_ICON_TEXT,
+                ConfigDefault::ROMANIZE_ICON_TEXT,
+            )?)?,
+            latin_names,
+            pending_query,
+        })
+    }
+
+    /// Whether names from a release that isn't resolved yet could change how the texts read.
+    pub fn awaits_latin_names(&self, details: &str, state: &str, icon_text: &str) -> bool {
+        let changes =
+            |mode: Romanization, text: &str| mode != Romanization::Original && !is_latin(text);
+
+        self.pending_query.is_some()
+            && (changes(self.details, details)
+                || changes(self.state, state)
+                || changes(self.icon_text, icon_text))
+    }
+
+    /// Picks up the Latin names of the pending release, resolving it if no cover lookup did.
+    pub fn resolve_latin_names(&mut self) -> Result<()> {
+        if let Some(query) = self.pending_query.take() {
+            self.latin_names = resolve_latin_names(&query)?;
+        }
+
+        Ok(())
+    }
+
+    fn romanize(&self, text: &str) -> String {
+        let text = self
+            .latin_names
+            .iter()
+            .fold(text.to_string(), |text, (original, latin)| {
+                text.replace(original, latin)
+            });
+
+        if is_japanese(&text) {
+            return text;
+        }
+
+        transliterate(&text)
+    }
+
+    pub fn details(&self, text: &str) -> String {
+        self.apply(self.details, text)
+    }
+
+    pub fn state(&self, text: &str) -> String {
+        self.apply(self.state, text)
+    }
+
+    pub fn icon_text(&self, text: &str) -> String {
+        self.apply(self.icon_text, text)
+    }
+
+    fn apply(&self, mode: Romanization, text: &str) -> String {
+        if is_latin(text) || mode == Romanization::Original {
+            return text.to_string();
+        }
+
+        let romanized = self.romanize(text);
+
+        match mode {
+            Romanization::Both if romanized != text => format!("{} ({})", text, romanized),
+            Romanization::Romanized => romanized,
+            _ => text.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn romanizer(mode: Romanization, pending_query: Option<&str>) -> Romanizer {
+        Romanizer {
+            details: mode,
+            state: mode,
+            icon_text: mode,
+            latin_names: Vec::new(),
+            pending_query: pending_query.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn only_non_latin_texts_await_latin_names() {
+        let pending = romanizer(Romanization::Romanized, Some("release:\"東京\""));
+
+        assert!(pending.awaits_latin_names("東京", "Artist", "Album"));
+        assert!(!pending.awaits_latin_names("Tokyo", "Artist", "Café"));
+        assert!(
+            !romanizer(Romanization::Original, Some("release:\"東京\""))
+                .awaits_latin_names("東京", "東京", "東京")
+        );
+        assert!(!romanizer(Romanization::Romanized, None).awaits_latin_names("東京", "", ""));
+    }
+
+    #[test]
+    fn latin_names_replace_before_transliterating() {
+        let mut romanizer = romanizer(Romanization::Both, None);
+
+        romanizer.latin_names = vec![("東京事変".to_string(), "Tokyo Jihen".to_string())];
+
+        assert_eq!(romanizer.details("東京事変"), "東京事変 (Tokyo Jihen)");
+        assert_eq!(romanizer.state("Café"), "Café");
+    }
+
+    #[test]
+    fn han_is_read_as_mandarin_unless_mixed_with_kana() {
+        let romanized = romanizer(Romanization::Romanized, None);
+        let both = romanizer(Romanization::Both, None);
+
+        assert_eq!(romanized.details("北京"), "Bei Jing");
+        assert_eq!(romanized.details("ひらがな"), "hiragana");
+        assert_eq!(romanized.details("東京の空"), "東京の空");
+        assert_eq!(both.details("東京の空"), "東京の空");
+    }
+}
@@ -0,0 +1,61 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    API,
+    config::{ConfigDefault, ConfigKey},
+    discordrpc::{MIN_UPDATE_INTERVAL, patch_state},
+    error::Result,
+    worker,
+};
+
+/// Bumped on every presence update so the previous track's rotation stops.
+static ROTATION_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Stops rotating the state of the previous presence and returns the generation for the next.
+pub fn stop_rotation() -> u64 {
+    ROTATION_GENERATION.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+struct RotationThreadData {
+    generation: u64,
+    track: usize,
+    /// Rendered states, the first one being shown already.
+    states: Vec<String>,
+    interval: Duration,
+}
+
+/// Cycles the state of `track`'s presence through `states` on the configured interval, until
+/// the rotation is stopped again after `generation`.
+pub fn start_rotation(generation: u64, track: usize, states: Vec<String>) -> Result<()> {
+    let api = API.get().unwrap();
+    let interval = api.conf_get_int(
+        ConfigKey::STATE_ROTATION_INTERVAL,
+        ConfigDefault::STATE_ROTATION_INTERVAL,
+    )?;
+    let data = RotationThreadData {
+        generation,
+        track,
+        states,
+        // Faster rotation would run into Discord's rate limit.
+        interval: Duration::from_secs(interval.max(0) as u64).max(MIN_UPDATE_INTERVAL),
+    };
+
+    worker::spawn_with("rotation_thread", rotation_thread, data)
+}
+
+fn rotation_thread(data: RotationThreadData) {
+    let api = API.get().unwrap();
+    let is_current = || ROTATION_GENERATION.load(Ordering::SeqCst) == data.generation;
+
+    for state in data.states.iter().cycle().skip(1) {
+        if !worker::sleep_while(data.interval, is_current) {
+            return;
+        }
+        if let Err(e) = patch_state(data.track, state.clone(), is_current) {
+            api.log_debug(format!("Failed to rotate the state: {:?}", e));
+        }
+    }
+}
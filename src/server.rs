@@ -0,0 +1,416 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use json::{JsonValue, object};
+use lazy_static::lazy_static;
+
+use crate::{
+    API,
+    config::{ConfigDefault, ConfigKey},
+    discordrpc::Presence,
+    error::{Error, Result},
+    sink::{PresenceSink, presence_json},
+    util::MutexExt,
+    worker,
+};
+
+/// How often the server checks for new connections and whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Comment lines sent to event stream clients so proxies and browsers keep them open.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Bumped whenever the server is stopped or restarted so the old thread exits.
+static SERVER_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Port the running server listens on.
+    static ref SERVER_PORT: Mutex<Option<u16>> = Mutex::new(None);
+    /// Latest `/nowplaying` payload.
+    static ref NOW_PLAYING: Mutex<JsonValue> = Mutex::new(object! { playing: false });
+    /// Cover URL of the current presence and its image once fetched.
+    static ref COVER: Mutex<(Option<String>, Option<Vec<u8>>)> = Mutex::new((None, None));
+    /// Open `/events` streams, non-blocking so a stalled client can't hold up the others.
+    static ref SUBSCRIBERS: Mutex<Vec<TcpStream>> = Mutex::new(Vec::new());
+    /// Latest event not yet sent to the streams; the server thread writes it out.
+    static ref PENDING_EVENT: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Serves the presence over localhost and pushes it to every event stream.
+///
+/// Kept up to date while the server is stopped too, so it starts out with the current track.
+pub struct ServerSink;
+
+impl PresenceSink for ServerSink {
+    fn name(&self) -> &'static str {
+        "server"
+    }
+
+    fn publish(&mut self, presence: &Presence) -> Result<()> {
+        update_now_playing(Some(presence));
+
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        update_now_playing(None);
+
+        Ok(())
+    }
+}
+
+fn update_now_playing(presence: Option<&Presence>) {
+    let now_playing = presence_json(presence);
+    let cover_url = presence
+        .map(|presence| presence.large_image.clone())
+        .filter(|image| image.starts_with("https://"));
+    let event = format!("data: {}\n\n", now_playing.dump());
+
+    {
+        let mut cover = COVER.lock_recover();
+
+        if cover.0 != cover_url {
+            *cover = (cover_url, None);
+        }
+    }
+    *NOW_PLAYING.lock_recover() = now_playing;
+
+    // Called with the sinks locked, so the streams are written from the server thread.
+    if SERVER_PORT.lock_recover().is_some() {
+        *PENDING_EVENT.lock_recover() = Some(event);
+    }
+}
+
+/// Writes `data` to every event stream, dropping the streams that fail or can't keep up.
+fn broadcast(data: &[u8]) {
+    SUBSCRIBERS
+        .lock_recover()
+        .retain_mut(|stream| stream.write_all(data).is_ok());
+}
+
+/// Starts, restarts or stops the server to match the settings.
+pub fn configure() -> Result<()> {
+    let api = API.get().unwrap();
+    let enable = api.conf_get_int(ConfigKey::ENABLE, ConfigDefault::ENABLE)? == 1
+        && api.conf_get_int(ConfigKey::SERVER_ENABLE, ConfigDefault::SERVER_ENABLE)? == 1;
+    let port = api.conf_get_int(ConfigKey::SERVER_PORT, ConfigDefault::SERVER_PORT)?;
+    let port = enable.then(|| u16::try_from(port).ok()).flatten();
+    let mut running = SERVER_PORT.lock_recover();
+
+    if *running == port {
+        return Ok(());
+    }
+
+    stop_locked(&mut running);
+
+    let Some(port) = port else {
+        return Ok(());
+    };
+
+    // Only reachable from this machine: overlays and dashboards run alongside the player.
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).map_err(Error::Io)?;
+
+    listener.set_nonblocking(true).map_err(Error::Io)?;
+
+    let data = ServerThreadData {
+        listener,
+        generation: SERVER_GENERATION.load(Ordering::SeqCst),
+    };
+
+    worker::spawn_with("server_thread", server_thread, data)?;
+
+    api.log_info(format!(
+        "Now playing server listening on http://127.0.0.1:{}/.",
+        port
+    ));
+    *running = Some(port);
+
+    Ok(())
+}
+
+pub fn stop() {
+    stop_locked(&mut SERVER_PORT.lock_recover());
+}
+
+fn stop_locked(running: &mut Option<u16>) {
+    if running.take().is_some() {
+        SERVER_GENERATION.fetch_add(1, Ordering::SeqCst);
+        SUBSCRIBERS.lock_recover().clear();
+        PENDING_EVENT.lock_recover().take();
+    }
+}
+
+struct ServerThreadData {
+    listener: TcpListener,
+    generation: u64,
+}
+
+fn server_thread(data: ServerThreadData) {
+    let api = API.get().unwrap();
+    let port = data.listener.local_addr().map_or(0, |addr| addr.port());
+    let mut last_keepalive = Instant::now();
+
+    while !worker::is_cancelled() && SERVER_GENERATION.load(Ordering::SeqCst) == data.generation {
+        match data.listener.accept() {
+            // Handled on their own thread, since `/cover` may have to download the image.
+            Ok((stream, _)) => {
+                if let Err(e) = worker::spawn_with(
+                    "server_request_thread",
+                    request_thread,
+                    RequestThreadData { stream, port },
+                ) {
+                    api.log_debug(format!("Failed to handle a server request: {:?}", e));
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                api.log_warn(format!("Now playing server stopped: {:?}", e));
+                break;
+            }
+        }
+
+        if let Some(event) = PENDING_EVENT.lock_recover().take() {
+            broadcast(event.as_bytes());
+        }
+        if last_keepalive.elapsed() >= KEEPALIVE_INTERVAL {
+            broadcast(b": keepalive\n\n");
+            last_keepalive = Instant::now();
+        }
+    }
+}
+
+struct RequestThreadData {
+    stream: TcpStream,
+    /// Port the server listens on, which the `Host` header must name.
+    port: u16,
+}
+
+fn request_thread(data: RequestThreadData) {
+    if let Err(e) = handle_connection(data.stream, data.port) {
+        API.get()
+            .unwrap()
+            .log_debug(format!("Now playing server request failed: {:?}", e));
+    }
+}
+
+struct Request {
+    /// Path of a GET request without the query, `None` for other methods.
+    path: Option<String>,
+    host: Option<String>,
+}
+
+/// Whether `host` addresses this server by its loopback address or name, so pages on other
+/// sites can't reach it by rebinding their own host name to 127.0.0.1.
+fn is_local_host(host: &str, port: u16) -> bool {
+    let host = host.to_ascii_lowercase();
+
+    host == format!("127.0.0.1:{}", port) || host == format!("localhost:{}", port)
+}
+
+/// Reads the request head and returns the GET path and `Host` header.
+fn read_request(stream: &mut TcpStream) -> Result<Option<Request>> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let len = stream.read(&mut buffer).map_err(Error::Io)?;
+
+        if len == 0 || request.len() > MAX_REQUEST_LEN {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buffer[..len]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let path = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(target)) => {
+            Some(target.split('?').next().unwrap_or_default().to_string())
+        }
+        _ => None,
+    };
+    let host = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim().to_string());
+
+    Ok(Some(Request { path, host }))
+}
+
+/// `Access-Control-Allow-Origin` line for the configured origin, empty if there is none.
+fn allow_origin_header() -> Result<String> {
+    let api = API.get().unwrap();
+    let origin = api.conf_get_str(
+        ConfigKey::SERVER_ALLOW_ORIGIN,
+        ConfigDefault::SERVER_ALLOW_ORIGIN,
+    )?;
+
+    Ok(match origin.trim() {
+        "" => String::new(),
+        origin => format!("Access-Control-Allow-Origin: {}\r\n", origin),
+    })
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         {}Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len(),
+        allow_origin_header()?
+    );
+
+    stream.write_all(head.as_bytes()).map_err(Error::Io)?;
+    stream.write_all(body).map_err(Error::Io)
+}
+
+/// The current cover, fetched on first request and kept until the cover changes.
+fn cover_image() -> Result<Option<Vec<u8>>> {
+    let api = API.get().unwrap();
+    let url = match &*COVER.lock_recover() {
+        (_, Some(image)) => return Ok(Some(image.clone())),
+        (Some(url), None) => url.clone(),
+        (None, None) => return Ok(None),
+    };
+    let image = api.http_get_bytes(&url)?;
+    let mut cover = COVER.lock_recover();
+
+    if cover.0.as_ref() == Some(&url) {
+        cover.1 = Some(image.clone());
+    }
+
+    Ok(Some(image))
+}
+
+fn handle_connection(mut stream: TcpStream, port: u16) -> Result<()> {
+    stream.set_nonblocking(false).map_err(Error::Io)?;
+    stream
+        .set_read_timeout(Some(CLIENT_TIMEOUT))
+        .map_err(Error::Io)?;
+    stream
+        .set_write_timeout(Some(CLIENT_TIMEOUT))
+        .map_err(Error::Io)?;
+
+    let Some(request) = read_request(&mut stream)? else {
+        return respond(&mut stream, "400 Bad Request", "text/plain", b"Bad request");
+    };
+
+    if !request
+        .host
+        .as_deref()
+        .is_some_and(|host| is_local_host(host, port))
+    {
+        return respond(&mut stream, "403 Forbidden", "text/plain", b"Forbidden");
+    }
+
+    let Some(path) = request.path else {
+        return respond(&mut stream, "400 Bad Request", "text/plain", b"Bad request");
+    };
+
+    match path.as_str() {
+        "/nowplaying" => {
+            let body = NOW_PLAYING.lock_recover().dump();
+
+            respond(&mut stream, "200 OK", "application/json", body.as_bytes())
+        }
+        "/cover" => match cover_image()? {
+            Some(image) => {
+                let content_type = image::guess_format(&image)
+                    .map(|format| format.to_mime_type())
+                    .unwrap_or("application/octet-stream");
+
+                respond(&mut stream, "200 OK", content_type, &image)
+            }
+            None => respond(&mut stream, "404 Not Found", "text/plain", b"No cover"),
+        },
+        "/events" => {
+            let event = format!("data: {}\n\n", NOW_PLAYING.lock_recover().dump());
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                 {}Cache-Control: no-cache\r\n\r\n",
+                allow_origin_header()?
+            );
+
+            stream.write_all(head.as_bytes()).map_err(Error::Io)?;
+            stream.write_all(event.as_bytes()).map_err(Error::Io)?;
+            stream.set_nonblocking(true).map_err(Error::Io)?;
+            SUBSCRIBERS.lock_recover().push(stream);
+
+            Ok(())
+        }
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"Not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deadbeef::testing;
+
+    /// Sends a GET request for `path` with the given `Host` header and returns the response.
+    fn get(port: u16, path: &str, host: &str) -> String {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        let mut response = String::new();
+
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, host).unwrap();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn free_port() -> u16 {
+        TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[test]
+    fn requests_need_a_local_host_and_get_no_cors_by_default() {
+        let _api = testing::setup();
+        let port = free_port();
+
+        testing::set_conf(ConfigKey::SERVER_ENABLE, 1);
+        testing::set_conf(ConfigKey::SERVER_PORT, port);
+        configure().unwrap();
+
+        let response = get(port, "/nowplaying", &format!("localhost:{}", port));
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(!response.contains("Access-Control-Allow-Origin"));
+        assert!(response.ends_with(&NOW_PLAYING.lock_recover().dump()));
+
+        let response = get(port, "/nowplaying", &format!("attacker.example:{}", port));
+
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+
+        testing::set_conf(ConfigKey::SERVER_ALLOW_ORIGIN, "https://overlay.example");
+
+        let response = get(port, "/nowplaying", &format!("127.0.0.1:{}", port));
+
+        assert!(response.contains("Access-Control-Allow-Origin: https://overlay.example\r\n"));
+
+        stop();
+    }
+
+    #[test]
+    fn only_loopback_hosts_are_local() {
+        assert!(is_local_host("127.0.0.1:6474", 6474));
+        assert!(is_local_host("LocalHost:6474", 6474));
+        assert!(!is_local_host("127.0.0.1:6475", 6474));
+        assert!(!is_local_host("127.0.0.1", 6474));
+        assert!(!is_local_host("evil.example:6474", 6474));
+        assert!(!is_local_host("localhost.evil.example:6474", 6474));
+    }
+}
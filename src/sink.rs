@@ -0,0 +1,202 @@
+use std::sync::Mutex;
+
+use json::{JsonValue, object};
+use lazy_static::lazy_static;
+
+use crate::{
+    API,
+    config::{ConfigDefault, ConfigKey},
+    discordrpc::{DiscordSink, Presence},
+    dryrun::DryRunSink,
+    error::Result,
+    export::FileSink,
+    listenbrainz::ListenBrainzSink,
+    server::ServerSink,
+    status,
+    util::{MutexExt, nowplaying_format_string},
+    webhook::WebhookSink,
+};
+
+/// Raw track fields sent alongside the presence, as JSON key and title format.
+const TRACK_FIELDS: [(&str, &str); 13] = [
+    ("title", "%title%"),
+    ("artist", "%artist%"),
+    ("album", "%album%"),
+    ("album_artist", "%album artist%"),
+    ("track_number", "%tracknumber%"),
+    ("year", "%year%"),
+    ("genre", "%genre%"),
+    ("length", "%length_seconds%"),
+    ("codec", "%codec%"),
+    ("bitrate", "%bitrate%"),
+    ("recording_mbid", "%musicbrainz_trackid%"),
+    ("release_mbid", "%musicbrainz_albumid%"),
+    ("artist_mbid", "%musicbrainz_artistid%"),
+];
+
+lazy_static! {
+    static ref SINKS: Mutex<Vec<Box<dyn PresenceSink>>> = Mutex::new(vec![
+        Box::new(LogSink),
+        Box::new(DiscordSink),
+        Box::new(DryRunSink),
+        Box::new(FileSink),
+        Box::new(ServerSink),
+        Box::new(WebhookSink::default()),
+        Box::new(ListenBrainzSink),
+    ]);
+}
+
+/// Somewhere the presence is published to, besides or instead of Discord.
+pub trait PresenceSink: Send {
+    /// Short name used in the log and the status report.
+    fn name(&self) -> &'static str;
+
+    /// Whether the sink is turned on in the settings.
+    fn enabled(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    /// Whether a failure is returned to the caller as the update's own error, rather than
+    /// only logged like a side channel's.
+    fn is_primary(&self) -> bool {
+        false
+    }
+
+    fn publish(&mut self, presence: &Presence) -> Result<()>;
+
+    /// Shows the idle presence used while playback is stopped; by default nothing is shown.
+    fn idle(&mut self, _presence: &Presence) -> Result<()> {
+        self.clear()
+    }
+
+    fn clear(&mut self) -> Result<()>;
+}
+
+/// What happened to the presence.
+#[derive(Debug, Clone, Copy)]
+pub enum SinkEvent<'a> {
+    Publish(&'a Presence),
+    Idle(&'a Presence),
+    Clear,
+}
+
+/// Passes `event` to every enabled sink; a failing sink doesn't keep the others from updating.
+///
+/// Returns the error of the primary sink (Discord), the others' are logged here.
+pub fn publish_all(event: SinkEvent) -> Result<()> {
+    let api = API.get().unwrap();
+    let mut primary_result = Ok(());
+
+    for sink in SINKS.lock_recover().iter_mut() {
+        let result = sink.enabled().and_then(|enabled| match event {
+            _ if !enabled => Ok(()),
+            SinkEvent::Publish(presence) => sink.publish(presence),
+            SinkEvent::Idle(presence) => sink.idle(presence),
+            SinkEvent::Clear => sink.clear(),
+        });
+
+        match result {
+            Err(e) if sink.is_primary() => primary_result = primary_result.and(Err(e)),
+            Err(e) => {
+                api.log_warn(format!(
+                    "Failed to update the {} presence: {:?}",
+                    sink.name(),
+                    e
+                ));
+                status::record_error(sink.name(), &e);
+            }
+            Ok(()) => {}
+        }
+    }
+
+    primary_result
+}
+
+/// Writes every presence change to the debug log.
+struct LogSink;
+
+impl PresenceSink for LogSink {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    fn publish(&mut self, presence: &Presence) -> Result<()> {
+        API.get().unwrap().log_debug(format!(
+            "Updating activity: details='{}', state='{}', large_image='{}', icon_text='{}'",
+            presence.details, presence.state, presence.large_image, presence.large_text
+        ));
+
+        Ok(())
+    }
+
+    fn idle(&mut self, presence: &Presence) -> Result<()> {
+        API.get().unwrap().log_debug(format!(
+            "Setting idle activity: details='{}', state='{}', large_image='{}'",
+            presence.details, presence.state, presence.large_image
+        ));
+
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        API.get()
+            .unwrap()
+            .log_debug("Clearing activity.".to_string());
+
+        Ok(())
+    }
+}
+
+/// Raw fields of the playing track, `None` unless a sink that sends them is enabled.
+pub fn render_track_fields() -> Result<Option<Vec<(&'static str, String)>>> {
+    let api = API.get().unwrap();
+
+    if api.conf_get_int(ConfigKey::SERVER_ENABLE, ConfigDefault::SERVER_ENABLE)? == 0
+        && !WebhookSink::default().enabled()?
+        && !ListenBrainzSink.enabled()?
+    {
+        return Ok(None);
+    }
+
+    let mut fields = Vec::new();
+
+    for (key, script) in TRACK_FIELDS {
+        fields.push((key, nowplaying_format_string(script)?));
+    }
+
+    Ok(Some(fields))
+}
+
+/// The presence as sent by the now playing server and the webhook, `playing: false` for none.
+pub fn presence_json(presence: Option<&Presence>) -> JsonValue {
+    let Some(presence) = presence else {
+        return object! { playing: false };
+    };
+    let mut track = JsonValue::new_object();
+
+    for (key, value) in presence.track_fields.iter().flatten() {
+        track[*key] = value.as_str().into();
+    }
+
+    object! {
+        playing: true,
+        details: presence.details.as_str(),
+        state: presence.state.as_str(),
+        large_text: presence.large_text.as_str(),
+        large_image: presence.large_image.as_str(),
+        buttons: presence
+            .buttons
+            .iter()
+            .map(|(label, url)| object! { label: label.as_str(), url: url.as_str() })
+            .collect::<Vec<_>>(),
+        party: presence
+            .party
+            .as_ref()
+            .map(|(_, [current, total])| object! { current: *current, total: *total }),
+        start_timestamp: presence.start_timestamp,
+        end_timestamp: presence.end_timestamp,
+        activity_type: format!("{:?}", presence.activity_kind).to_lowercase(),
+        status_display: format!("{:?}", presence.status_display).to_lowercase(),
+        track: track,
+    }
+}
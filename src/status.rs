@@ -0,0 +1,89 @@
+use std::{collections::BTreeMap, fmt::Write, fs, sync::Mutex, time::SystemTime};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    API,
+    cover::cache_stats,
+    error::{Error, Result},
+    util::{MutexExt, open_with_default_app, plugin_config_dir},
+};
+
+lazy_static! {
+    static ref STATUS: Mutex<PluginStatus> = Mutex::new(PluginStatus::default());
+}
+
+/// What the plugin is doing, as last reported by the message handler and the workers.
+#[derive(Debug, Default)]
+struct PluginStatus {
+    /// Connected Discord clients, with the user each is logged in as when known.
+    clients: Vec<String>,
+    /// JSON of the last activity sent to Discord, `None` once it was cleared.
+    last_activity: Option<String>,
+    /// Last failure of each subsystem and when it happened.
+    errors: BTreeMap<&'static str, (SystemTime, String)>,
+}
+
+pub fn set_clients(clients: Vec<String>) {
+    STATUS.lock_recover().clients = clients;
+}
+
+pub fn set_last_activity(payload: Option<String>) {
+    STATUS.lock_recover().last_activity = payload;
+}
+
+/// Remembers `error` as the latest failure of `subsystem` ("connection", "cover", a sink...).
+pub fn record_error(subsystem: &'static str, error: &Error) {
+    STATUS
+        .lock_recover()
+        .errors
+        .insert(subsystem, (SystemTime::now(), format!("{:?}", error)));
+}
+
+fn summary() -> String {
+    let status = STATUS.lock_recover();
+    let (cached_covers, pending_covers) = cache_stats();
+    let mut out = String::from("Discord Rich Presence status\n\n");
+
+    if status.clients.is_empty() {
+        out.push_str("Connection: not connected\n");
+    } else {
+        for client in &status.clients {
+            let _ = writeln!(out, "Connection: {}", client);
+        }
+    }
+
+    let _ = writeln!(
+        out,
+        "Last activity: {}",
+        status.last_activity.as_deref().unwrap_or("none")
+    );
+    let _ = writeln!(
+        out,
+        "Cover cache: {} covers, {} lookups in progress",
+        cached_covers, pending_covers
+    );
+
+    out.push_str("\nLast errors:\n");
+    if status.errors.is_empty() {
+        out.push_str("  none\n");
+    }
+    for (subsystem, (time, error)) in &status.errors {
+        let ago = time.elapsed().map(|ago| ago.as_secs()).unwrap_or_default();
+        let _ = writeln!(out, "  {} ({}s ago): {}", subsystem, ago, error);
+    }
+
+    out
+}
+
+/// Writes the status summary to the log and to `status.txt`, then opens the file.
+pub fn show_status() -> Result<()> {
+    let api = API.get().unwrap();
+    let summary = summary();
+    let path = plugin_config_dir()?.join("status.txt");
+
+    api.log_info(summary.clone());
+    fs::write(&path, summary).map_err(Error::Io)?;
+
+    open_with_default_app(&path)
+}
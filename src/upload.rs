@@ -0,0 +1,146 @@
+use std::{fs, path::PathBuf, sync::Mutex};
+
+use json::JsonValue;
+use lazy_static::lazy_static;
+
+use crate::{
+    API,
+    artwork::prepare_cover,
+    config::{ConfigDefault, ConfigKey, UploadMethod},
+    curl::{self, Body},
+    error::{Error, Result},
+    util::{MutexExt, content_hash, plugin_config_dir},
+};
+
+lazy_static! {
+    /// Content hash to published URL, mirrored in `uploaded_covers.json`.
+    static ref UPLOADED: Mutex<Option<JsonValue>> = Mutex::new(None);
+}
+
+fn cache_path() -> Result<PathBuf> {
+    Ok(plugin_config_dir()?.join("uploaded_covers.json"))
+}
+
+fn cached_url(hash: &str) -> Result<Option<String>> {
+    let mut uploaded = UPLOADED.lock_recover();
+
+    if uploaded.is_none() {
+        let cache = fs::read_to_string(cache_path()?)
+            .ok()
+            .and_then(|raw| json::parse(&raw).ok())
+            .filter(JsonValue::is_object)
+            .unwrap_or_else(JsonValue::new_object);
+
+        *uploaded = Some(cache);
+    }
+
+    Ok(uploaded
+        .as_ref()
+        .and_then(|cache| cache[hash].as_str())
+        .map(str::to_string))
+}
+
+fn store_url(hash: &str, url: &str) -> Result<()> {
+    let mut uploaded = UPLOADED.lock_recover();
+    let cache = uploaded.get_or_insert_with(JsonValue::new_object);
+
+    cache[hash] = url.into();
+    fs::write(cache_path()?, cache.pretty(2)).map_err(Error::Io)
+}
+
+/// Publishes `source` scaled to fit `max_pixels` through the configured uploader, once per
+/// distinct image and size.
+pub fn publish_cover(source: &[u8], max_pixels: Option<u32>) -> Result<String> {
+    let api = API.get().unwrap();
+    let method = UploadMethod::try_from(
+        api.conf_get_int(ConfigKey::UPLOAD_METHOD, ConfigDefault::UPLOAD_METHOD)?,
+    )?;
+
+    if let UploadMethod::Disabled = method {
+        return Err(Error::UploaderDisabled);
+    }
+
+    // Keyed on the original image so a known cover is never decoded and re-encoded again.
+    let hash = format!(
+        "{}-{}",
+        content_hash(source),
+        max_pixels.map_or("original".to_string(), |size| size.to_string())
+    );
+
+    if let Some(url) = cached_url(&hash)? {
+        return Ok(url);
+    }
+
+    let data = prepare_cover(source, max_pixels)?;
+    let url = match method {
+        UploadMethod::Http => upload_http(&data, &hash)?,
+        UploadMethod::SyncedDirectory => copy_to_synced_dir(&data, &hash)?,
+        UploadMethod::Disabled => return Err(Error::UploaderDisabled),
+    };
+
+    api.log_info(format!("Published local cover {} as {}", hash, url));
+    store_url(&hash, &url)?;
+
+    Ok(url)
+}
+
+fn upload_http(data: &[u8], hash: &str) -> Result<String> {
+    let api = API.get().unwrap();
+    let url = api.conf_get_str(ConfigKey::UPLOAD_URL, ConfigDefault::UPLOAD_URL)?;
+    let field = api.conf_get_str(ConfigKey::UPLOAD_FIELD, ConfigDefault::UPLOAD_FIELD)?;
+    let header = api.conf_get_str(ConfigKey::UPLOAD_HEADER, ConfigDefault::UPLOAD_HEADER)?;
+    let json_path =
+        api.conf_get_str(ConfigKey::UPLOAD_JSON_PATH, ConfigDefault::UPLOAD_JSON_PATH)?;
+
+    if url.is_empty() {
+        return Err(Error::UploaderDisabled);
+    }
+
+    let headers = if header.is_empty() {
+        vec![]
+    } else {
+        vec![header]
+    };
+    let response = curl::post(
+        &url,
+        &headers,
+        Body::File {
+            field: &field,
+            file_name: &format!("{}.jpg", hash),
+            data,
+        },
+    )?;
+    let response = json::parse(&response).map_err(Error::JsonParseFailed)?;
+
+    json_path_str(&response, &json_path).ok_or(Error::UploadResponseMissingUrl)
+}
+
+/// Looks up a dotted path such as `data.files.0.url` in `value`.
+pub fn json_path_str(value: &JsonValue, path: &str) -> Option<String> {
+    path.split('.')
+        .filter(|key| !key.is_empty())
+        .try_fold(value, |value, key| match value {
+            JsonValue::Array(items) => items.get(key.parse::<usize>().ok()?),
+            JsonValue::Object(object) => object.get(key),
+            _ => None,
+        })
+        .and_then(JsonValue::as_str)
+        .map(str::to_string)
+}
+
+fn copy_to_synced_dir(data: &[u8], hash: &str) -> Result<String> {
+    let api = API.get().unwrap();
+    let dir = api.conf_get_str(ConfigKey::SYNC_DIR, ConfigDefault::SYNC_DIR)?;
+    let base_url = api.conf_get_str(ConfigKey::SYNC_BASE_URL, ConfigDefault::SYNC_BASE_URL)?;
+
+    if dir.is_empty() || base_url.is_empty() {
+        return Err(Error::UploaderDisabled);
+    }
+
+    let file_name = format!("{}.jpg", hash);
+
+    fs::create_dir_all(&dir).map_err(Error::Io)?;
+    fs::write(PathBuf::from(&dir).join(&file_name), data).map_err(Error::Io)?;
+
+    Ok(format!("{}/{}", base_url.trim_end_matches('/'), file_name))
+}
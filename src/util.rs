@@ -1,20 +1,76 @@
 use std::{
     ffi::{CStr, c_char},
-    mem,
+    fs, mem,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Mutex, MutexGuard, PoisonError},
 };
 
 use crate::{
     API,
-    deadbeef::{PL_MAIN, ddb_tf_context_t},
+    deadbeef::{
+        DDB_SYS_DIR_CONFIG, PL_MAIN, ddb_tf_context_t,
+        safe_wrapper::{SafeDBPlayItem, SafeDBPlayList},
+    },
     error::{Error, Result},
 };
 
 static MAX_LEN: usize = 256;
 
+pub trait MutexExt<T> {
+    /// Locks the mutex, recovering the data if a panicking thread poisoned it.
+    fn lock_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    fn lock_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// FNV-1a, so the hash (and the cache keyed by it) is stable across builds.
+pub fn content_hash(data: &[u8]) -> String {
+    let hash = data.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    format!("{:016x}", hash)
+}
+
+/// Runs an exported entry point, logging any panic and returning `fallback` instead of
+/// unwinding into DeaDBeeF.
+pub fn catch_panic<T>(name: &str, fallback: T, f: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(ret) => ret,
+        Err(payload) => {
+            let reason = payload
+                .downcast_ref::<&str>()
+                .map(|reason| reason.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+
+            // Without the API there is nowhere to report it; DeaDBeeF hasn't loaded us yet.
+            if let Some(api) = API.get() {
+                api.log_error(format!("Panic in {}: {}", name, reason));
+            }
+
+            fallback
+        }
+    }
+}
+
 pub fn nowplaying_format_string(script: &str) -> Result<String> {
     let api = API.get().unwrap();
     let nowplaying = api.streamer_get_playing_track()?;
     let nowplaying_plt = api.plt_get_curr()?;
+
+    format_string(&nowplaying, &nowplaying_plt, script)
+}
+
+/// Evaluates a title formatting `script` against `item` in `plt`.
+pub fn format_string(item: &SafeDBPlayItem, plt: &SafeDBPlayList, script: &str) -> Result<String> {
+    let api = API.get().unwrap();
     let code_script = api.tf_compile(script)?;
 
     let mut context: Box<ddb_tf_context_t> = unsafe { Box::new(mem::zeroed()) };
@@ -22,8 +78,8 @@ pub fn nowplaying_format_string(script: &str) -> Result<String> {
     let out_ptr = out.as_mut_ptr() as *mut c_char;
 
     context._size = std::mem::size_of::<ddb_tf_context_t>() as i32;
-    context.it = nowplaying.as_ptr();
-    context.plt = nowplaying_plt.as_ptr();
+    context.it = item.as_ptr();
+    context.plt = plt.as_ptr();
     context.iter = PL_MAIN as i32;
 
     if !code_script.is_null() {
@@ -35,17 +91,78 @@ pub fn nowplaying_format_string(script: &str) -> Result<String> {
     Ok(c_str.to_string_lossy().to_string())
 }
 
-pub fn nowplaying_length() -> Result<f32> {
+/// Length of `item` in seconds, or `None` when DeaDBeeF doesn't know it.
+///
+/// CUE subtracks share one image file, so the length is taken from the item's
+/// start/end sample range when the samplerate is available.
+pub fn item_length(item: &SafeDBPlayItem) -> Result<Option<f32>> {
     let api = API.get().unwrap();
-    let nowplaying = api.streamer_get_playing_track()?;
 
-    if !nowplaying.is_null() {
-        Ok(API.get().unwrap().pl_get_item_duration(&nowplaying)?)
+    if item.is_null() {
+        return Ok(None);
+    }
+
+    let start_sample = api.pl_item_get_startsample(item)?;
+    let end_sample = api.pl_item_get_endsample(item)?;
+    let samplerate = api.pl_find_meta_int(item, c":SAMPLERATE".as_ptr(), 0)?;
+    let duration = api.pl_get_item_duration(item)?;
+
+    Ok(track_length(start_sample, end_sample, samplerate, duration))
+}
+
+/// Length of the `start_sample..end_sample` range, falling back to `duration` (-1 if unknown).
+fn track_length(start_sample: i64, end_sample: i64, samplerate: i32, duration: f32) -> Option<f32> {
+    if samplerate > 0 && end_sample > start_sample {
+        // In floating point, as a broken range can span more than an i64.
+        Some(((end_sample as f64 - start_sample as f64) / samplerate as f64) as f32)
+    } else if duration >= 0.0 {
+        Some(duration)
     } else {
-        Ok(0.0)
+        None
     }
 }
 
+pub fn nowplaying_length() -> Result<Option<f32>> {
+    let api = API.get().unwrap();
+    let nowplaying = api.streamer_get_playing_track()?;
+
+    item_length(&nowplaying)
+}
+
+/// Seconds played in the current track, relative to the subtrack start for CUE items.
+pub fn nowplaying_position() -> Result<f32> {
+    let api = API.get().unwrap();
+    let position = api.streamer_get_playpos()?;
+
+    Ok(match nowplaying_length()? {
+        Some(length) => position.clamp(0.0, length),
+        None => position.max(0.0),
+    })
+}
+
+/// Copies `item`'s metadata value for `key`, if the tag is present.
+pub fn item_meta(item: &SafeDBPlayItem, key: &CStr) -> Result<Option<String>> {
+    let api = API.get().unwrap();
+
+    if item.is_null() {
+        return Ok(None);
+    }
+
+    api.pl_lock()?;
+
+    let result = api.pl_find_meta(item, key.as_ptr()).map(|value| {
+        (!value.is_null()).then(|| {
+            unsafe { CStr::from_ptr(value) }
+                .to_string_lossy()
+                .to_string()
+        })
+    });
+
+    api.pl_unlock()?;
+
+    result
+}
+
 pub fn is_streaming() -> Result<bool> {
     let api = API.get().unwrap();
     let nowplaying = api.streamer_get_playing_track()?;
@@ -64,3 +181,63 @@ pub fn is_streaming() -> Result<bool> {
         Ok(false)
     }
 }
+
+/// Directory for files written by the plugin, inside DeaDBeeF's config directory.
+pub fn plugin_config_dir() -> Result<PathBuf> {
+    let api = API.get().unwrap();
+    let dir = PathBuf::from(api.get_system_dir(DDB_SYS_DIR_CONFIG)?).join("discordrpc");
+
+    fs::create_dir_all(&dir).map_err(Error::Io)?;
+
+    Ok(dir)
+}
+
+/// Opens `path` with the desktop's default application.
+pub fn open_with_default_app(path: &Path) -> Result<()> {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else {
+        Command::new("xdg-open")
+    };
+
+    command.arg(path).spawn().map_err(Error::Io)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_return_the_fallback() {
+        assert_eq!(catch_panic("test", -1, || panic!("boom")), -1);
+        assert_eq!(catch_panic("test", -1, || 0), 0);
+    }
+
+    #[test]
+    fn cue_subtrack_length_comes_from_its_sample_range() {
+        // Second subtrack of an image file: starts 10 s in and runs for 200 s, while the
+        // duration reported for the item is that of the whole image.
+        let length = track_length(441_000, 441_000 + 200 * 44_100, 44_100, 3_000.0);
+
+        assert_eq!(length, Some(200.0));
+    }
+
+    #[test]
+    fn length_falls_back_to_the_duration_without_a_sample_range() {
+        assert_eq!(track_length(0, 0, 0, 245.5), Some(245.5));
+        assert_eq!(track_length(0, 0, 48_000, 245.5), Some(245.5));
+        assert_eq!(track_length(0, 1_000, 0, 245.5), Some(245.5));
+    }
+
+    #[test]
+    fn unknown_duration_has_no_length() {
+        assert_eq!(track_length(0, 0, 0, -1.0), None);
+        assert_eq!(track_length(0, -1, 44_100, -1.0), None);
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    API,
+    config::{ConfigDefault, ConfigKey},
+    curl::{self, Body},
+    discordrpc::Presence,
+    error::Result,
+    sink::{PresenceSink, presence_json},
+    status, worker,
+};
+
+const MAX_ATTEMPTS: u32 = 3;
+/// Waited before the second attempt, doubling for each one after it.
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Bumped for every payload so retries of an outdated one are dropped.
+static WEBHOOK_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// POSTs the presence as JSON to a configurable URL on track change.
+#[derive(Debug, Default)]
+pub struct WebhookSink {
+    /// Playing item and play the last payload was sent for, `None` after a stop was sent.
+    last_play: Option<(usize, u64)>,
+    /// Whether the last payload went out before the track's cover was resolved.
+    cover_pending: bool,
+}
+
+impl WebhookSink {
+    fn post(&self, body: String) -> Result<()> {
+        let api = API.get().unwrap();
+        let url = api.conf_get_str(ConfigKey::WEBHOOK_URL, ConfigDefault::WEBHOOK_URL)?;
+        let headers =
+            api.conf_get_str(ConfigKey::WEBHOOK_HEADERS, ConfigDefault::WEBHOOK_HEADERS)?;
+        let data = WebhookThreadData {
+            url: url.trim().to_string(),
+            headers: headers
+                .split('|')
+                .map(str::trim)
+                .filter(|header| !header.is_empty())
+                .map(str::to_string)
+                .collect(),
+            body,
+            sequence: WEBHOOK_SEQUENCE.fetch_add(1, Ordering::SeqCst) + 1,
+        };
+
+        // Sent from its own worker so retries never hold up the other sinks.
+        worker::spawn_with("webhook_thread", webhook_thread, data)
+    }
+}
+
+impl PresenceSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn enabled(&self) -> Result<bool> {
+        let api = API.get().unwrap();
+
+        Ok(!api
+            .conf_get_str(ConfigKey::WEBHOOK_URL, ConfigDefault::WEBHOOK_URL)?
+            .trim()
+            .is_empty())
+    }
+
+    fn publish(&mut self, presence: &Presence) -> Result<()> {
+        let resolved_cover = self.cover_pending && presence.cover_key.is_none();
+
+        // Pauses and seeks within the same play aren't worth a request.
+        if self.last_play == Some((presence.track, presence.play)) && !resolved_cover {
+            return Ok(());
+        }
+
+        self.last_play = Some((presence.track, presence.play));
+        self.cover_pending = presence.cover_key.is_some();
+        self.post(presence_json(Some(presence)).dump())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        if self.last_play.take().is_none() {
+            return Ok(());
+        }
+
+        self.cover_pending = false;
+        self.post(presence_json(None).dump())
+    }
+}
+
+struct WebhookThreadData {
+    url: String,
+    /// Extra headers as "Name: value".
+    headers: Vec<String>,
+    body: String,
+    sequence: u64,
+}
+
+fn webhook_thread(data: WebhookThreadData) {
+    let api = API.get().unwrap();
+    let mut delay = RETRY_DELAY;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        match curl::post(&data.url, &data.headers, Body::Json(&data.body)) {
+            Ok(_) => return,
+            Err(e) if attempt == MAX_ATTEMPTS => {
+                api.log_warn(format!(
+                    "Failed to send webhook after {} attempts: {:?}",
+                    MAX_ATTEMPTS, e
+                ));
+                status::record_error("webhook", &e);
+            }
+            Err(e) => {
+                api.log_debug(format!(
+                    "Webhook attempt {} failed, retrying in {} seconds: {:?}",
+                    attempt,
+                    delay.as_secs(),
+                    e
+                ));
+
+                if !worker::sleep_while(delay, || {
+                    WEBHOOK_SEQUENCE.load(Ordering::SeqCst) == data.sequence
+                }) {
+                    return;
+                }
+                delay *= 2;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::RecvTimeoutError;
+
+    use super::*;
+    use crate::deadbeef::testing::{self, presence};
+
+    /// Long enough for curl to start and for one retry delay.
+    const WAIT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn sends_headers_and_json_body() {
+        let _api = testing::setup();
+        let (url, requests) = testing::serve(Vec::new());
+        let presence = presence(1);
+
+        testing::set_conf(ConfigKey::WEBHOOK_URL, format!("{}/hook", url));
+        testing::set_conf(
+            ConfigKey::WEBHOOK_HEADERS,
+            "Authorization: Bearer secret | X-Source: deadbeef",
+        );
+        WebhookSink::default().publish(&presence).unwrap();
+
+        let request = requests.recv_timeout(WAIT).unwrap();
+
+        assert!(request.head.starts_with("POST /hook HTTP/1.1"));
+        assert_eq!(request.header("Authorization"), Some("Bearer secret"));
+        assert_eq!(request.header("X-Source"), Some("deadbeef"));
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        assert_eq!(
+            json::parse(&request.body).unwrap(),
+            presence_json(Some(&presence))
+        );
+    }
+
+    #[test]
+    fn retries_failed_requests() {
+        let _api = testing::setup();
+        let (url, requests) = testing::serve(vec![500, 500]);
+
+        testing::set_conf(ConfigKey::WEBHOOK_URL, url);
+        WebhookSink::default().publish(&presence(1)).unwrap();
+
+        let bodies = (0..MAX_ATTEMPTS)
+            .map(|_| requests.recv_timeout(WAIT).unwrap().body)
+            .collect::<Vec<_>>();
+
+        assert!(bodies.iter().all(|body| *body == bodies[0]));
+        assert_eq!(
+            requests.recv_timeout(RETRY_DELAY * 2).unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+    }
+
+    #[test]
+    fn newer_payloads_cancel_pending_retries() {
+        let _api = testing::setup();
+        let (url, requests) = testing::serve(vec![500]);
+        let mut sink = WebhookSink::default();
+
+        testing::set_conf(ConfigKey::WEBHOOK_URL, url);
+        sink.publish(&presence(1)).unwrap();
+
+        let failed = requests.recv_timeout(WAIT).unwrap();
+
+        sink.clear().unwrap();
+
+        let stopped = requests.recv_timeout(WAIT).unwrap();
+
+        assert_eq!(json::parse(&failed.body).unwrap()["playing"], true);
+        assert_eq!(json::parse(&stopped.body).unwrap()["playing"], false);
+        // The first payload would have been retried after RETRY_DELAY.
+        assert_eq!(
+            requests.recv_timeout(RETRY_DELAY * 2).unwrap_err(),
+            RecvTimeoutError::Timeout
+        );
+    }
+}
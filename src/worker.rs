@@ -0,0 +1,127 @@
+use std::{
+    ffi::c_void,
+    sync::{
+        Condvar, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    API,
+    error::{Error, Result},
+    util::{MutexExt, catch_panic},
+};
+
+/// How often sleeping workers check whether they should give up.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set while the plugin is stopping; pending lookups bail out and no new work is started.
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+lazy_static! {
+    static ref WORKERS: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+}
+
+/// Keeps a worker counted as running until it is dropped at the end of the thread function.
+struct WorkerGuard;
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        let (count, done) = &*WORKERS;
+        let mut count = count.lock_recover();
+
+        *count = count.saturating_sub(1);
+        done.notify_all();
+    }
+}
+
+/// Starts `func` on a DeaDBeeF thread and tracks it so shutdown can wait for it.
+///
+/// `func` must hold a [`WorkerGuard`] for as long as it runs.
+fn spawn(func: unsafe extern "C" fn(*mut c_void), args: *mut c_void) -> Result<isize> {
+    if is_cancelled() {
+        return Err(Error::Cancelled);
+    }
+
+    *WORKERS.0.lock_recover() += 1;
+
+    let ret = API
+        .get()
+        .ok_or(Error::MissingFunction)
+        .and_then(|api| api.thread_start(func, args));
+
+    if ret.is_err() {
+        drop(WorkerGuard);
+    }
+
+    ret
+}
+
+struct Task<T> {
+    name: &'static str,
+    func: fn(T),
+    data: T,
+}
+
+/// Runs `func(data)` on a tracked worker thread, logging a panic under `name`.
+pub fn spawn_with<T: Send + 'static>(name: &'static str, func: fn(T), data: T) -> Result<()> {
+    let task = Box::into_raw(Box::new(Task { name, func, data }));
+
+    if let Err(e) = spawn(run_task::<T>, task as *mut c_void) {
+        drop(unsafe { Box::from_raw(task) });
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+extern "C" fn run_task<T>(ptr: *mut c_void) {
+    let _worker = WorkerGuard;
+    let Task { name, func, data } = *unsafe { Box::from_raw(ptr as *mut Task<T>) };
+
+    catch_panic(name, (), || func(data));
+}
+
+/// Sleeps for `duration`, returning `false` as soon as `is_current` fails or the plugin stops.
+pub fn sleep_while(duration: Duration, is_current: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + duration;
+
+    loop {
+        if is_cancelled() || !is_current() {
+            return false;
+        }
+
+        let left = deadline.saturating_duration_since(Instant::now());
+
+        if left.is_zero() {
+            return true;
+        }
+        thread::sleep(left.min(POLL_INTERVAL));
+    }
+}
+
+pub fn is_cancelled() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+pub fn cancel() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn resume() {
+    SHUTTING_DOWN.store(false, Ordering::SeqCst);
+}
+
+/// Waits up to `timeout` for all running workers, returning `false` if some are still busy.
+pub fn wait_idle(timeout: Duration) -> bool {
+    let (count, done) = &*WORKERS;
+    let count = count.lock_recover();
+    let (count, _) = done
+        .wait_timeout_while(count, timeout, |count| *count > 0)
+        .unwrap_or_else(PoisonError::into_inner);
+
+    *count == 0
+}